@@ -1,12 +1,21 @@
-use anyhow::{anyhow, Error, Result};
-use clap::Parser;
+use anyhow::{Error, Result, anyhow};
+use clap::{Parser, Subcommand};
 use colored::*;
-use std::io::{prelude::*, IsTerminal};
+use std::io::{IsTerminal, prelude::*};
 use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
 
-use crate::{console::Verbosity, replace, Console, DirectoryPatcher, Query, Settings};
+use crate::atomic;
+use crate::case::{CaseOptions, CaseVariant};
+use crate::encoding;
+use crate::journal::{self, Journal};
+use crate::plan::Plan;
+use crate::query::{Engine, RegexOptions, has_uppercase, preserve_case_regex_query, regex_query};
+use crate::{
+    Console, DirectoryPatcher, LineFilter, NodeKind, OutputFormat, Query, Rule, Settings, Stats,
+    SyntaxFilter, console::Verbosity, replace,
+};
 
 #[derive(Debug, Clone, Copy)]
 enum ColorWhen {
@@ -32,6 +41,8 @@ impl std::str::FromStr for ColorWhen {
 #[command(
     name = "ruplacer",
     version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
     after_help = "
 Examples:
     Replace 'foo' with 'bar'
@@ -47,9 +58,18 @@ Examples:
 
     Replace 'FooBar' with 'SpamEggs', 'foo_bar' with 'spam_eggs', ...
     $ ruplacer --preserve-case FooBar SpamEggs
+
+    Apply all the rules from 'rules.toml' to the files in 'src/':
+    $ ruplacer --rules rules.toml src/
+
+    Restore the files changed by the latest run with --go:
+    $ ruplacer undo
 "
 )]
 struct Options {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(long = "go", help = "Write the changes to the filesystem")]
     go: bool,
 
@@ -59,11 +79,19 @@ struct Options {
     )]
     quiet: bool,
 
-    #[arg(help = "The pattern to search for")]
-    pattern: String,
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        conflicts_with = "quiet",
+        help = "Also show the files that were skipped, and why"
+    )]
+    verbose: bool,
 
-    #[arg(help = "The replacement")]
-    replacement: String,
+    #[arg(required_unless_present = "rules", help = "The pattern to search for")]
+    pattern: Option<String>,
+
+    #[arg(required_unless_present = "rules", help = "The replacement")]
+    replacement: Option<String>,
 
     #[arg(
         value_parser = PathBuf::from_str,
@@ -96,6 +124,119 @@ struct Options {
     )]
     preserve_case: bool,
 
+    #[arg(
+        long = "case-variants",
+        value_delimiter = ',',
+        requires = "preserve_case",
+        help = "Comma-separated list of the case variants to replace with --preserve-case. Choose between 'ada' (Foo_Bar), 'camel' (fooBar), 'kebab' (foo-bar), 'pascal' (FooBar), 'screaming' (FOO_BAR), 'snake' (foo_bar), 'train' (Foo-Bar), 'dot' (foo.bar), 'path' (foo/bar), 'title' (Foo Bar), 'space' (foo bar), 'flat' (foobar) or 'upper-flat' (FOOBAR). Default is all the variants up to 'train'"
+    )]
+    case_variants: Vec<CaseVariant>,
+
+    #[arg(
+        long = "acronyms",
+        value_delimiter = ',',
+        requires = "preserve_case",
+        help = "Comma-separated list of words to also replace when written as acronyms with --preserve-case, like HTTP in HTTPServer. Common acronyms like HTTP, ID or URL are always recognized"
+    )]
+    acronyms: Vec<String>,
+
+    #[arg(
+        long = "inflect-plurals",
+        requires = "preserve_case",
+        help = "With --preserve-case, also replace the plural forms of the pattern with the plural forms of the replacement"
+    )]
+    inflect_plurals: bool,
+
+    #[arg(
+        long = "case-regex",
+        requires = "preserve_case",
+        conflicts_with = "inflect_plurals",
+        help = "With --preserve-case, interpret the pattern as a regex written in snake case instead of a raw string. Each '_' also matches the separators of the other case variants, and the replacement is converted to the case variant of each match"
+    )]
+    case_regex: bool,
+
+    #[arg(
+        long = "case-escapes",
+        conflicts_with_all = ["no_regex", "structural"],
+        help = "Interpret \\U, \\L, \\E, \\u and \\l in the replacement as sed-like case conversions, and \\\\ as a literal backslash. Only for regex replacements"
+    )]
+    case_escapes: bool,
+
+    #[arg(
+        long = "structural",
+        conflicts_with_all = ["no_regex", "word_regex", "preserve_case", "engine"],
+        help = "Interpret pattern as a structural pattern with holes like 'foo(:[args])', matching balanced parentheses, brackets and braces. The holes can be used in the replacement"
+    )]
+    structural: bool,
+
+    #[arg(
+        short = 'i',
+        long = "ignore-case",
+        conflicts_with_all = ["preserve_case", "structural"],
+        help = "Match the pattern regardless of case"
+    )]
+    ignore_case: bool,
+
+    #[arg(
+        short = 'S',
+        long = "smart-case",
+        conflicts_with_all = ["preserve_case", "ignore_case", "structural"],
+        help = "Match the pattern regardless of case, unless it contains an upper case letter"
+    )]
+    smart_case: bool,
+
+    #[arg(
+        long = "engine",
+        conflicts_with_all = ["no_regex", "preserve_case"],
+        help = "Regex engine. Choose between 'default' or 'fancy' (supports lookaround and backreferences, but is slower). Default is 'default'"
+    )]
+    engine: Option<Engine>,
+
+    #[arg(
+        long = "only",
+        value_delimiter = ',',
+        help = "Only replace the matches inside the given kinds of syntax nodes, among 'identifiers', 'strings' and 'comments'. Files in other languages than C, Go, JavaScript, Python, Rust and TypeScript are left untouched"
+    )]
+    only: Vec<NodeKind>,
+
+    #[arg(
+        long = "skip",
+        value_delimiter = ',',
+        help = "Don't replace the matches in the given kinds of syntax nodes, among 'identifiers', 'strings' and 'comments'. Same languages as --only"
+    )]
+    skip: Vec<NodeKind>,
+
+    #[arg(
+        long = "only-lines",
+        value_name = "REGEX",
+        value_parser = regex::Regex::new,
+        help = "Only replace on the lines matching the given regex"
+    )]
+    only_lines: Option<regex::Regex>,
+
+    #[arg(
+        long = "skip-lines",
+        value_name = "REGEX",
+        value_parser = regex::Regex::new,
+        help = "Don't replace on the lines matching the given regex"
+    )]
+    skip_lines: Option<regex::Regex>,
+
+    #[arg(
+        long = "skip-region",
+        num_args = 2,
+        value_names = ["START", "END"],
+        value_parser = regex::Regex::new,
+        help = "Don't replace between the lines matching START and END, included. Can be used several times"
+    )]
+    skip_region: Vec<regex::Regex>,
+
+    #[arg(
+        long = "skip-generated",
+        help = "Skip files containing '@generated' or 'DO NOT EDIT' in their first lines"
+    )]
+    skip_generated: bool,
+
     #[arg(
         short = 't',
         long = "type",
@@ -127,21 +268,153 @@ struct Options {
         help = "Exits without error when no matches are found."
     )]
     allow_empty: bool,
+
+    #[arg(
+        short = 'U',
+        long = "multiline",
+        help = "Match the pattern against whole files, so that matches can span several lines. In this mode, '^' and '$' match at line boundaries, and '.' also matches new lines"
+    )]
+    multiline: bool,
+
+    #[arg(
+        long = "interactive",
+        conflicts_with_all = ["format", "diff", "patch_file"],
+        help = "Ask before applying each replacement, then write the accepted ones to the filesystem"
+    )]
+    interactive: bool,
+
+    #[arg(
+        long = "format",
+        help = "Output format. Choose between 'text' (colored diffs), 'json' (one JSON object per match, then a summary), or 'diff' (same as --diff). Default is 'text'"
+    )]
+    format: Option<OutputFormat>,
+
+    #[arg(
+        long = "diff",
+        conflicts_with_all = ["go", "format"],
+        help = "Print the changes as a unified diff, suitable for 'git apply' or 'patch -p1'"
+    )]
+    diff: bool,
+
+    #[arg(
+        long = "context",
+        help = "Number of context lines in unified diffs, with --diff or --patch-file. Default is 3"
+    )]
+    context_lines: Option<usize>,
+
+    #[arg(
+        long = "patch-file",
+        conflicts_with = "go",
+        help = "Write the changes as a unified diff to the given path"
+    )]
+    patch_file: Option<PathBuf>,
+
+    #[arg(
+        short = 'j',
+        long = "threads",
+        default_value_t = 0,
+        help = "Number of threads to use. 0 means choosing automatically"
+    )]
+    threads: usize,
+
+    #[arg(
+        long = "rules",
+        conflicts_with_all = ["interactive", "no_regex", "word_regex", "preserve_case", "ignore_case", "smart_case", "engine", "case_variants", "acronyms", "inflect_plurals", "case_regex", "structural", "case_escapes"],
+        help = "Apply the rules from the given TOML file instead of a single pattern and replacement. When used, the first positional argument is the source path"
+    )]
+    rules: Option<PathBuf>,
+
+    #[arg(
+        long = "preserve-mtime",
+        help = "Keep the modification time of the patched files"
+    )]
+    preserve_mtime: bool,
+
+    #[arg(
+        long = "encoding",
+        value_parser = encoding::parse_encoding,
+        help = "Encoding of the files without a byte order mark, like 'latin1' or 'utf-16le'. Default is UTF-8"
+    )]
+    encoding: Option<&'static encoding_rs::Encoding>,
+
+    #[arg(
+        long = "max-filesize",
+        value_parser = parse_size,
+        help = "Skip files bigger than the given size. Use the K, M or G suffixes for kilobytes, megabytes or gigabytes"
+    )]
+    max_filesize: Option<u64>,
+
+    #[arg(long = "git-tracked", help = "Only patch files tracked by git")]
+    git_tracked: bool,
+
+    #[arg(
+        long = "changed-since",
+        value_name = "REV",
+        help = "Only patch files that differ from the given git revision, including untracked files"
+    )]
+    changed_since: Option<String>,
+
+    #[arg(
+        long = "allow-dirty",
+        help = "Write changes even to files with uncommitted changes in their git repository"
+    )]
+    allow_dirty: bool,
+
+    #[arg(
+        long = "save-plan",
+        conflicts_with_all = ["go", "interactive"],
+        help = "Save the replacements to the given path, so that they can be applied later with `ruplacer apply`"
+    )]
+    save_plan: Option<PathBuf>,
+
+    #[arg(
+        long = "rename-paths",
+        conflicts_with_all = ["interactive", "diff", "patch_file", "save_plan"],
+        help = "Also rename the files and directories whose names match, after patching the files"
+    )]
+    rename_paths: bool,
 }
 
-fn regex_query_or_die(pattern: &str, replacement: &str, word: bool) -> Query {
-    let actual_pattern = if word {
-        format!(r"\b({})\b", pattern)
-    } else {
-        pattern.to_string()
+// Parse sizes like "512", "10K" or "2M"
+fn parse_size(value: &str) -> Result<u64> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'G')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
     };
-    let re = regex::Regex::new(&actual_pattern);
-    if let Err(e) = re {
-        eprintln!("{}: {}", "Invalid regex".bold().red(), e);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size: {value}"))?;
+    Ok(number * multiplier)
+}
+
+fn query_or_die(query: Result<Query>) -> Query {
+    query.unwrap_or_else(|e| {
+        eprintln!("{}: {:#}", "Error".bold().red(), e);
         process::exit(1);
-    }
-    let re = re.unwrap();
-    Query::regex(re, replacement)
+    })
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Restore the files changed by a previous run with --go
+    Undo {
+        #[arg(help = "The id of the run to undo. Defaults to the latest one")]
+        run_id: Option<String>,
+
+        #[arg(
+            long,
+            default_value = ".",
+            help = "The path given to the run to undo (default: the current directory)"
+        )]
+        path: PathBuf,
+    },
+    /// Apply the replacements saved with --save-plan, without matching the files again
+    Apply {
+        #[arg(help = "The plan file")]
+        plan: PathBuf,
+    },
 }
 
 // Set proper env variable so that the colored crate behaves properly.
@@ -198,6 +471,7 @@ pub fn run() -> Result<()> {
         }
     };
     let Options {
+        command,
         color_when,
         file_type_list: _,
         go,
@@ -211,30 +485,84 @@ pub fn run() -> Result<()> {
         replacement,
         selected_file_types,
         preserve_case,
+        case_variants,
+        acronyms,
+        inflect_plurals,
+        case_regex,
+        structural,
+        case_escapes,
+        only,
+        skip,
+        only_lines,
+        skip_lines,
+        skip_region,
+        skip_generated,
+        ignore_case,
+        smart_case,
+        engine,
         word_regex,
         allow_empty,
+        multiline,
+        interactive,
+        format,
+        diff,
+        context_lines,
+        patch_file,
+        threads,
+        rules,
+        preserve_mtime,
+        encoding,
+        verbose,
+        max_filesize,
+        git_tracked,
+        changed_since,
+        allow_dirty,
+        save_plan,
+        rename_paths,
     } = opt;
 
-    let dry_run = !go;
+    match command {
+        Some(Command::Undo { run_id, path }) => return run_undo(run_id.as_deref(), &path),
+        Some(Command::Apply { plan }) => return run_apply(&plan),
+        None => (),
+    }
+
+    // Note: --format diff is the same as --diff, which clap can't tell, so
+    // check the options of unified diffs here
+    let diff = diff || format == Some(OutputFormat::Diff);
+    if diff && go {
+        return Err(anyhow!("Cannot use --go with --diff or --format diff"));
+    }
+    if diff && rename_paths {
+        return Err(anyhow!(
+            "Cannot use --rename-paths with --diff or --format diff"
+        ));
+    }
+    if context_lines.is_some() && !diff && patch_file.is_none() {
+        return Err(anyhow!(
+            "Cannot use --context without --diff or --patch-file"
+        ));
+    }
+
+    let dry_run = !go && !interactive;
     let verbosity = if quiet {
         Verbosity::Quiet
+    } else if verbose {
+        Verbosity::Verbose
     } else {
         Verbosity::Normal
     };
-    let console = Console::with_verbosity(verbosity);
+    let format = if diff {
+        OutputFormat::Diff
+    } else {
+        format.unwrap_or_default()
+    };
+    let console = Console::with_format(verbosity, format);
 
     let color_when = &color_when.unwrap_or(ColorWhen::Auto);
     configure_color(color_when);
 
-    let query = if no_regex {
-        Query::simple(&pattern, &replacement)
-    } else if preserve_case {
-        Query::preserve_case(&pattern, &replacement)
-    } else {
-        regex_query_or_die(&pattern, &replacement, word_regex)
-    };
-
-    let settings = Settings {
+    let mut settings = Settings {
         verbosity,
         dry_run,
         hidden,
@@ -242,26 +570,197 @@ pub fn run() -> Result<()> {
         selected_file_types,
         ignored_file_types,
         allow_empty,
+        multiline,
+        interactive,
+        context_lines,
+        patch_file,
+        threads,
+        // Note: set once the path is known, see below
+        history_dir: None,
+        preserve_mtime,
+        encoding,
+        max_filesize,
+        git_tracked,
+        changed_since,
+        allow_dirty,
+        plan_file: save_plan,
+        rename_paths,
+        syntax_filter: SyntaxFilter { only, skip },
+        line_filter: LineFilter {
+            only: only_lines,
+            skip: skip_lines,
+            skip_regions: skip_region
+                .chunks(2)
+                .map(|markers| (markers[0].clone(), markers[1].clone()))
+                .collect(),
+        },
+        skip_generated,
+        excluded_files: vec![],
+    };
+
+    // Note: when using a rules file, there is no pattern nor replacement,
+    // so the first positional argument is the path
+    let (rules, path) = match &rules {
+        Some(rules_path) => {
+            if replacement.is_some() {
+                return Err(anyhow!(
+                    "Cannot use a pattern and a replacement with --rules"
+                ));
+            }
+            let rules = Rule::from_file(rules_path, &settings)?;
+            // Note: the rules file may be inside the patched directory, and
+            // would then be patched by its own rules
+            if let Ok(rules_path) = rules_path.canonicalize() {
+                settings.excluded_files.push(rules_path);
+            }
+            (rules, pattern.map(PathBuf::from))
+        }
+        None => {
+            // Note: both are required by clap unless --rules is used
+            let pattern = pattern.unwrap_or_default();
+            let replacement = replacement.unwrap_or_default();
+            let ignore_case = ignore_case || (smart_case && !has_uppercase(&pattern, !no_regex));
+            let query = if no_regex && ignore_case {
+                Query::simple_ignore_case(&pattern, &replacement)
+            } else if no_regex {
+                Query::simple(&pattern, &replacement)
+            } else if structural {
+                query_or_die(Query::structural(&pattern, &replacement))
+            } else {
+                let options = RegexOptions {
+                    word: word_regex,
+                    multiline,
+                    ignore_case,
+                    engine: engine.unwrap_or_default(),
+                    case_escapes,
+                };
+                let case_options = if case_variants.is_empty() {
+                    CaseOptions::default()
+                } else {
+                    CaseOptions::with_variants(&case_variants)
+                };
+                let case_options = case_options
+                    .with_acronyms(&acronyms)
+                    .with_plurals(inflect_plurals);
+                if preserve_case && !case_regex && case_escapes {
+                    return Err(anyhow!(
+                        "Cannot use --case-escapes with --preserve-case, unless --case-regex is used"
+                    ));
+                }
+                if preserve_case && case_regex {
+                    let query =
+                        preserve_case_regex_query(&pattern, &replacement, options, case_options);
+                    query_or_die(query)
+                } else if preserve_case {
+                    Query::preserve_case_with(&pattern, &replacement, case_options)
+                } else {
+                    let query = regex_query(&pattern, &replacement, options);
+                    query_or_die(query)
+                }
+            };
+            (vec![Rule::new(query)], path)
+        }
     };
 
     let path = path.unwrap_or_else(|| Path::new(".").to_path_buf());
-    if path == PathBuf::from("-") {
-        run_on_stdin(query)
+    if path.as_os_str() == "-" {
+        if !settings.syntax_filter.is_empty() {
+            return Err(anyhow!(
+                "Cannot use --only or --skip when reading from stdin"
+            ));
+        }
+        if !settings.line_filter.skip_regions.is_empty() || settings.skip_generated {
+            return Err(anyhow!(
+                "Cannot use --skip-region or --skip-generated when reading from stdin"
+            ));
+        }
+        if multiline && !settings.line_filter.is_empty() {
+            return Err(anyhow!(
+                "Cannot use --only-lines or --skip-lines with --multiline when reading from stdin"
+            ));
+        }
+        run_on_stdin(&rules, multiline, &settings.line_filter)
     } else {
-        run_on_directory(console, path, settings, query)
+        if !dry_run {
+            settings.history_dir = Some(journal::default_history_dir(&path));
+        }
+        let show_rules = rules.len() > 1;
+        run_on_directory(console, path, settings, &rules, show_rules)
     }
 }
 
-fn run_on_stdin(query: Query) -> Result<()> {
+fn run_undo(run_id: Option<&str>, path: &Path) -> Result<()> {
+    let (run_id, num_changes) = journal::undo(&journal::default_history_dir(path), run_id)?;
+    let change_string = if num_changes > 1 { "changes" } else { "change" };
+    println!("Reverted {num_changes} {change_string} made by run {run_id}");
+    Ok(())
+}
+
+// Note: files that changed since the plan was saved are left untouched,
+// but the other ones are still patched
+fn run_apply(plan_path: &Path) -> Result<()> {
+    let plan = Plan::load(plan_path)?;
+    let mut journal = None;
+    let mut num_failed = 0;
+    for file_plan in plan.files() {
+        let new_contents = match file_plan.new_contents() {
+            Ok(new_contents) => new_contents,
+            Err(e) => {
+                eprintln!("{}: {:#}", "Error".bold().red(), e);
+                num_failed += 1;
+                continue;
+            }
+        };
+        let journal = match &mut journal {
+            Some(journal) => journal,
+            None => journal.insert(Journal::create(&journal::default_history_dir(
+                &file_plan.path,
+            ))?),
+        };
+        journal.record(&file_plan.path, &new_contents)?;
+        atomic::write_file(&file_plan.path, &new_contents, false)?;
+    }
+    let num_applied = plan.files().len() - num_failed;
+    let file_string = if num_applied == 1 { "file" } else { "files" };
+    println!("Patched {num_applied} {file_string}");
+    if let Some(journal) = &journal {
+        println!(
+            "Original contents saved as run {}. Use `ruplacer undo` to restore them",
+            journal.run_id()
+        );
+    }
+    if num_failed > 0 {
+        return Err(anyhow!(
+            "{num_failed} file(s) could not be patched, see above"
+        ));
+    }
+    Ok(())
+}
+
+fn run_on_stdin(rules: &[Rule], multiline: bool, line_filter: &LineFilter) -> Result<()> {
+    let apply = |input: &str| -> Result<String> {
+        let mut output = input.to_string();
+        for rule in rules {
+            if let Some(replacement) = replace(&output, rule.query())? {
+                output = replacement.output().to_string();
+            }
+        }
+        Ok(output)
+    };
     let stdin = std::io::stdin();
+    if multiline {
+        let mut input = String::new();
+        stdin.lock().read_to_string(&mut input)?;
+        print!("{}", apply(&input)?);
+        return Ok(());
+    }
     for line in stdin.lock().lines() {
         let line = line?;
-        let replacement = replace(&line, &query);
-        if let Some(replacement) = replacement {
-            println!("{}", replacement.output());
-        } else {
-            println!("{}", line);
+        if !line_filter.allows(&line) {
+            println!("{line}");
+            continue;
         }
+        println!("{}", apply(&line)?);
     }
     Ok(())
 }
@@ -270,13 +769,24 @@ fn run_on_directory(
     console: Console,
     path: PathBuf,
     settings: Settings,
-    query: Query,
+    rules: &[Rule],
+    show_rules: bool,
 ) -> Result<()> {
     let dry_run = settings.dry_run;
     let mut directory_patcher = DirectoryPatcher::new(&console, &path, &settings);
-    directory_patcher.run(&query)?;
+    directory_patcher.run_rules(rules)?;
     let stats = directory_patcher.stats();
-    if stats.total_replacements() == 0 {
+    console.print_json_summary(&stats, dry_run);
+    // Note: print this first, so that it's visible even when nothing
+    // was found to replace
+    if let Some(summary) = stats.skipped_summary() {
+        let hint = match settings.verbosity {
+            Verbosity::Verbose => "",
+            _ => " - use --verbose for details",
+        };
+        console.print_message(&format!("{summary}{hint}\n"));
+    }
+    if stats.total_replacements() == 0 && stats.renamed_paths() == 0 {
         match settings.allow_empty {
             true => {
                 console.print_message("nothing found to replace\n");
@@ -301,6 +811,19 @@ fn run_on_directory(
     };
     console.print_message(message);
     console.print_message(&format!("{stats}\n"));
+    if settings.rename_paths {
+        let renamed_paths = stats.renamed_paths();
+        let path_string = if renamed_paths > 1 { "paths" } else { "path" };
+        let message = if dry_run { "Would rename" } else { "Renamed" };
+        console.print_message(&format!("{message} {renamed_paths} {path_string}\n"));
+    }
+    if show_rules {
+        let no_match = Stats::default();
+        for (i, rule) in rules.iter().enumerate() {
+            let rule_stats = stats.rules().get(i).unwrap_or(&no_match);
+            console.print_message(&format!("  {}: {rule_stats}\n", rule.description()));
+        }
+    }
 
     if dry_run {
         console
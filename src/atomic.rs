@@ -0,0 +1,187 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Write `contents` to the file at `path`, without leaving it half-written
+/// if something goes wrong.
+///
+/// The contents is written to a temporary file in the same directory, which
+/// gets the permissions (and on Unix, the owner) of the original file, and
+/// is then renamed over it. If `preserve_mtime` is true, the modification time
+/// of the original file is kept too.
+///
+/// Special cases:
+///  * if `path` is a symbolic link, the file it points to is patched and the link is left as is
+///  * if the file has several hard links, or if its owner can't be preserved, it is written
+///    in place (renaming would break the links or change the owner)
+pub(crate) fn write_file(path: &Path, contents: &[u8], preserve_mtime: bool) -> Result<()> {
+    let target = fs::canonicalize(path)
+        .with_context(|| format!("Could not canonicalize {}", path.display()))?;
+    let metadata = fs::metadata(&target)
+        .with_context(|| format!("Could not read metadata of {}", path.display()))?;
+
+    if has_several_links(&metadata) {
+        return write_in_place(&target, contents, &metadata, preserve_mtime);
+    }
+
+    let parent = target.parent().unwrap_or_else(|| Path::new("."));
+    let mut temp_file = tempfile::Builder::new()
+        .prefix(".ruplacer")
+        .tempfile_in(parent)
+        .with_context(|| format!("Could not create temporary file in {}", parent.display()))?;
+    let file = temp_file.as_file_mut();
+    if !copy_owner(file, &metadata) {
+        return write_in_place(&target, contents, &metadata, preserve_mtime);
+    }
+    file.write_all(contents)
+        .and_then(|_| file.set_permissions(metadata.permissions()))
+        .and_then(|_| set_mtime(file, &metadata, preserve_mtime))
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Could not write temporary file for {}", path.display()))?;
+    temp_file
+        .persist(&target)
+        .with_context(|| format!("Could not replace {}", path.display()))?;
+    sync_dir(parent);
+    Ok(())
+}
+
+fn write_in_place(
+    target: &Path,
+    contents: &[u8],
+    metadata: &Metadata,
+    preserve_mtime: bool,
+) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(target)
+        .with_context(|| format!("Could not open {}", target.display()))?;
+    file.write_all(contents)
+        .and_then(|_| set_mtime(&file, metadata, preserve_mtime))
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Could not write {}", target.display()))
+}
+
+fn set_mtime(file: &File, metadata: &Metadata, preserve_mtime: bool) -> std::io::Result<()> {
+    if !preserve_mtime {
+        return Ok(());
+    }
+    file.set_modified(metadata.modified()?)
+}
+
+#[cfg(unix)]
+fn has_several_links(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn has_several_links(_metadata: &Metadata) -> bool {
+    false
+}
+
+// Return false if the owner of the new file could not be set
+#[cfg(unix)]
+fn copy_owner(file: &File, metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let new_metadata = match file.metadata() {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    if new_metadata.uid() == metadata.uid() && new_metadata.gid() == metadata.gid() {
+        return true;
+    }
+    std::os::unix::fs::fchown(file, Some(metadata.uid()), Some(metadata.gid())).is_ok()
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_file: &File, _metadata: &Metadata) -> bool {
+    true
+}
+
+// Make sure the rename is persisted. This is best effort, since
+// not all platforms support opening directories
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, temp_dir};
+
+    #[test]
+    fn test_write_file() {
+        let temp_dir = temp_dir();
+        let path = test_utils::write_file(temp_dir.path(), "foo.txt", "old");
+
+        write_file(&path, b"new", false).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        let leftovers = fs::read_dir(temp_dir.path()).unwrap().count();
+        assert_eq!(leftovers, 1);
+    }
+
+    #[test]
+    fn test_preserve_mtime() {
+        let temp_dir = temp_dir();
+        let path = test_utils::write_file(temp_dir.path(), "foo.txt", "old");
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        write_file(&path, b"new", true).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp_dir = temp_dir();
+        let path = test_utils::write_file(temp_dir.path(), "script.sh", "echo old");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        write_file(&path, b"echo new", false).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_symlinks() {
+        let temp_dir = temp_dir();
+        let target = temp_dir.path().join("target.txt");
+        let link = temp_dir.path().join("link.txt");
+        fs::write(&target, "old").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        write_file(&link, b"new", false).unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_keep_hard_links() {
+        let temp_dir = temp_dir();
+        let path = temp_dir.path().join("foo.txt");
+        let other = temp_dir.path().join("other.txt");
+        fs::write(&path, "old").unwrap();
+        fs::hard_link(&path, &other).unwrap();
+
+        write_file(&path, b"new", false).unwrap();
+
+        assert_eq!(fs::read_to_string(&other).unwrap(), "new");
+    }
+}
@@ -0,0 +1,527 @@
+use anyhow::{Result, bail};
+use inflector::cases::{
+    camelcase::to_camel_case, kebabcase::to_kebab_case, pascalcase::to_pascal_case,
+    screamingsnakecase::to_screaming_snake_case, snakecase::to_snake_case,
+    titlecase::to_title_case, traincase::to_train_case,
+};
+use inflector::string::pluralize::to_plural;
+use serde::Deserialize;
+
+/// A case style, used by preserve-case queries to find all the variants
+/// of the pattern and convert the replacement accordingly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseVariant {
+    /// Foo_Bar
+    Ada,
+    /// fooBar
+    Camel,
+    /// foo-bar
+    Kebab,
+    /// FooBar
+    Pascal,
+    /// FOO_BAR
+    #[serde(rename = "screaming")]
+    ScreamingSnake,
+    /// foo_bar
+    Snake,
+    /// Foo-Bar
+    Train,
+    /// foo.bar
+    Dot,
+    /// foo/bar
+    Path,
+    /// Foo Bar
+    Title,
+    /// foo bar
+    Space,
+    /// foobar
+    Flat,
+    /// FOOBAR
+    UpperFlat,
+}
+
+/// The variants used when none are selected. The other ones are more
+/// likely to match unrelated text, so they must be enabled explicitly
+pub(crate) const DEFAULT_VARIANTS: &[CaseVariant] = &[
+    CaseVariant::Ada,
+    CaseVariant::Camel,
+    CaseVariant::Kebab,
+    CaseVariant::Pascal,
+    CaseVariant::ScreamingSnake,
+    CaseVariant::Snake,
+    CaseVariant::Train,
+];
+
+impl std::str::FromStr for CaseVariant {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let variant = match s {
+            "ada" => CaseVariant::Ada,
+            "camel" => CaseVariant::Camel,
+            "kebab" => CaseVariant::Kebab,
+            "pascal" => CaseVariant::Pascal,
+            "screaming" => CaseVariant::ScreamingSnake,
+            "snake" => CaseVariant::Snake,
+            "train" => CaseVariant::Train,
+            "dot" => CaseVariant::Dot,
+            "path" => CaseVariant::Path,
+            "title" => CaseVariant::Title,
+            "space" => CaseVariant::Space,
+            "flat" => CaseVariant::Flat,
+            "upper-flat" => CaseVariant::UpperFlat,
+            _ => bail!(
+                "Unknown case variant: '{s}'. Choose between 'ada', 'camel', 'kebab', 'pascal', \
+                 'screaming', 'snake', 'train', 'dot', 'path', 'title', 'space', 'flat' or 'upper-flat'"
+            ),
+        };
+        Ok(variant)
+    }
+}
+
+impl CaseVariant {
+    /// Convert `input`, written in any case style, to this one
+    pub(crate) fn convert(self, input: &str) -> String {
+        // Note: Inflector does not split words like "HTTPServer" correctly,
+        // except when converting to snake case
+        let normalized;
+        let input = if detect_acronyms(input).is_empty() {
+            input
+        } else {
+            normalized = to_snake_case(input);
+            &normalized
+        };
+        // Note: the variants not supported by Inflector are built
+        // from the snake case, by changing the separator
+        let with_separator = |separator| to_snake_case(input).replace('_', separator);
+        match self {
+            CaseVariant::Ada => to_train_case(input).replace('-', "_"),
+            CaseVariant::Camel => to_camel_case(input),
+            CaseVariant::Kebab => to_kebab_case(input),
+            CaseVariant::Pascal => to_pascal_case(input),
+            CaseVariant::ScreamingSnake => to_screaming_snake_case(input),
+            CaseVariant::Snake => to_snake_case(input),
+            CaseVariant::Train => to_train_case(input),
+            CaseVariant::Dot => with_separator("."),
+            CaseVariant::Path => with_separator("/"),
+            CaseVariant::Title => to_title_case(input),
+            CaseVariant::Space => with_separator(" "),
+            CaseVariant::Flat => with_separator(""),
+            CaseVariant::UpperFlat => with_separator("").to_uppercase(),
+        }
+    }
+
+    // The separator between words, if any
+    fn separator(self) -> Option<char> {
+        match self {
+            CaseVariant::Ada | CaseVariant::ScreamingSnake | CaseVariant::Snake => Some('_'),
+            CaseVariant::Kebab | CaseVariant::Train => Some('-'),
+            CaseVariant::Dot => Some('.'),
+            CaseVariant::Path => Some('/'),
+            CaseVariant::Title | CaseVariant::Space => Some(' '),
+            CaseVariant::Camel
+            | CaseVariant::Pascal
+            | CaseVariant::Flat
+            | CaseVariant::UpperFlat => None,
+        }
+    }
+
+    // Join the words, writing the acronyms in upper case. Return None
+    // for the variants where acronyms are not written differently
+    fn join(self, words: &[Word]) -> Option<String> {
+        let capitalized = || words.iter().map(Word::capitalized);
+        let joined = match self {
+            CaseVariant::Ada => capitalized().collect::<Vec<_>>().join("_"),
+            CaseVariant::Pascal => capitalized().collect(),
+            CaseVariant::Train => capitalized().collect::<Vec<_>>().join("-"),
+            CaseVariant::Title => capitalized().collect::<Vec<_>>().join(" "),
+            CaseVariant::Camel => {
+                let (first, rest) = words.split_first()?;
+                std::iter::once(first.text.clone())
+                    .chain(rest.iter().map(Word::capitalized))
+                    .collect()
+            }
+            _ => return None,
+        };
+        Some(joined)
+    }
+}
+
+/// Options of a preserve-case query
+#[derive(Debug, Clone)]
+pub struct CaseOptions {
+    variants: Vec<CaseVariant>,
+    // In lower case
+    acronyms: Vec<String>,
+    plurals: bool,
+}
+
+impl Default for CaseOptions {
+    fn default() -> Self {
+        Self::with_variants(DEFAULT_VARIANTS)
+    }
+}
+
+impl CaseOptions {
+    /// Only look for the given case variants
+    pub fn with_variants(variants: &[CaseVariant]) -> Self {
+        Self {
+            variants: variants.to_vec(),
+            acronyms: DEFAULT_ACRONYMS.iter().map(|s| s.to_string()).collect(),
+            plurals: false,
+        }
+    }
+
+    /// Also write the given words (in any case) as acronyms in the variants
+    /// mixing upper and lower case, in addition to the default acronyms
+    pub fn with_acronyms(mut self, acronyms: &[String]) -> Self {
+        self.acronyms
+            .extend(acronyms.iter().map(|acronym| acronym.to_lowercase()));
+        self
+    }
+
+    /// Also replace the plural forms of the pattern with the plural forms
+    /// of the replacement, like `categories` with `groups`
+    pub fn with_plurals(mut self, plurals: bool) -> Self {
+        self.plurals = plurals;
+        self
+    }
+
+    /// Return the (pattern, replacement) pairs for each variant, in order.
+    /// When several variants of the pattern are the same, the first one wins
+    pub(crate) fn items(&self, pattern: &str, replacement: &str) -> Vec<(String, String)> {
+        let mut items = vec![];
+        // Note: plural forms come first, so that "boxes" is not replaced
+        // as "box" followed by "es"
+        if self.plurals {
+            self.push_items(&mut items, &pluralize(pattern), &pluralize(replacement));
+        }
+        self.push_items(&mut items, pattern, replacement);
+        items
+    }
+
+    fn push_items(&self, items: &mut Vec<(String, String)>, pattern: &str, replacement: &str) {
+        let mut push = |pattern: String, replacement: String| {
+            if !items.iter().any(|(other, _)| *other == pattern) {
+                items.push((pattern, replacement));
+            }
+        };
+        for variant in &self.variants {
+            push(variant.convert(pattern), variant.convert(replacement));
+        }
+        // Then, the same variants with the acronyms in upper case, like
+        // HTTPServer for http_server
+        let pattern_words = self.words(pattern);
+        if pattern_words.iter().any(|word| word.is_acronym) {
+            let replacement_words = self.words(replacement);
+            for variant in &self.variants {
+                if let (Some(pattern), Some(replacement)) = (
+                    variant.join(&pattern_words),
+                    variant.join(&replacement_words),
+                ) {
+                    push(pattern, replacement);
+                }
+            }
+        }
+    }
+
+    /// Return a regex matching the separators between words in the
+    /// selected variants, or nothing, like `(?:_|-)?`
+    pub(crate) fn separators_regex(&self) -> String {
+        let mut separators: Vec<char> = self
+            .variants
+            .iter()
+            .filter_map(|variant| variant.separator())
+            .collect();
+        separators.sort();
+        separators.dedup();
+        let alternatives: Vec<String> = separators
+            .iter()
+            .map(|separator| regex::escape(&separator.to_string()))
+            .collect();
+        format!("(?:{})?", alternatives.join("|"))
+    }
+
+    /// Convert `replacement` to the case variant `text` is written in, or
+    /// return None if `text` is not written in one of the selected variants.
+    /// `glued` tells whether some words of `text` are written without any
+    /// separator nor case change, like in "foobar", which only the flat
+    /// variants allow
+    pub(crate) fn convert_like(
+        &self,
+        text: &str,
+        replacement: &str,
+        glued: bool,
+    ) -> Option<String> {
+        let variants = || {
+            self.variants.iter().filter(move |variant| {
+                !glued || matches!(variant, CaseVariant::Flat | CaseVariant::UpperFlat)
+            })
+        };
+        if let Some(variant) = variants().find(|variant| variant.convert(text) == text) {
+            return Some(variant.convert(replacement));
+        }
+        // Maybe the text contains acronyms, like HTTPServer
+        let words = self.words(text);
+        if words.iter().any(|word| word.is_acronym) {
+            let replacement_words = self.words(replacement);
+            for variant in variants() {
+                if variant.join(&words).as_deref() == Some(text) {
+                    return variant.join(&replacement_words);
+                }
+            }
+        }
+        None
+    }
+
+    // Split `input` into words, and tell which ones are acronyms: the
+    // known ones, and the ones written in upper case in mixed-case input
+    fn words(&self, input: &str) -> Vec<Word> {
+        let detected = detect_acronyms(input);
+        to_snake_case(input)
+            .split('_')
+            .filter(|word| !word.is_empty())
+            .map(|word| Word {
+                text: word.to_string(),
+                is_acronym: self.acronyms.iter().any(|acronym| acronym == word)
+                    || detected.iter().any(|acronym| acronym == word),
+            })
+            .collect()
+    }
+}
+
+/// Acronyms written in upper case in the variants mixing upper and lower
+/// case, unless configured otherwise
+pub(crate) const DEFAULT_ACRONYMS: &[&str] = &[
+    "api", "cpu", "css", "dns", "gpu", "grpc", "html", "http", "https", "id", "io", "ip", "json",
+    "rpc", "sql", "ssl", "tcp", "tls", "udp", "ui", "uri", "url", "uuid", "xml",
+];
+
+#[derive(Debug)]
+struct Word {
+    // In lower case
+    text: String,
+    is_acronym: bool,
+}
+
+impl Word {
+    fn capitalized(&self) -> String {
+        if self.is_acronym {
+            return self.text.to_uppercase();
+        }
+        let mut chars = self.text.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        }
+    }
+}
+
+// Return `input` with its last word in the plural, keeping its case.
+// For instance, "FooCategories" for "FooCategory"
+fn pluralize(input: &str) -> String {
+    let chars: Vec<(usize, char)> = input.char_indices().collect();
+    let mut start = 0;
+    for (i, &(index, c)) in chars.iter().enumerate().skip(1) {
+        let previous = chars[i - 1].1;
+        let next = chars.get(i + 1).map(|&(_, c)| c);
+        let is_boundary = !previous.is_alphanumeric()
+            || (c.is_uppercase() && !previous.is_uppercase())
+            || (c.is_uppercase() && next.is_some_and(char::is_lowercase));
+        if is_boundary && c.is_alphanumeric() {
+            start = index;
+        }
+    }
+    let (prefix, word) = input.split_at(start);
+    let lower_case = word.to_lowercase();
+    // Note: Inflector gets a few irregular plurals wrong
+    let plural = match lower_case.as_str() {
+        "person" => "people".to_string(),
+        "mouse" => "mice".to_string(),
+        "louse" => "lice".to_string(),
+        _ => to_plural(&lower_case),
+    };
+    let plural = if word.len() > 1 && !word.chars().any(char::is_lowercase) {
+        plural.to_uppercase()
+    } else if word.starts_with(char::is_uppercase) {
+        let mut chars = plural.chars();
+        chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect())
+            .unwrap_or_default()
+    } else {
+        plural
+    };
+    format!("{prefix}{plural}")
+}
+
+// Return the runs of upper case letters in `input`, in lower case, if it
+// also contains lower case letters. For instance, "http" for "HTTPServer"
+fn detect_acronyms(input: &str) -> Vec<String> {
+    if !input.chars().any(char::is_lowercase) {
+        return vec![];
+    }
+    let chars: Vec<char> = input.chars().collect();
+    let mut acronyms = vec![];
+    let mut start = 0;
+    while start < chars.len() {
+        if !chars[start].is_uppercase() {
+            start += 1;
+            continue;
+        }
+        let mut end = start;
+        while end < chars.len() && chars[end].is_uppercase() {
+            end += 1;
+        }
+        // In "HTTPServer", the 'S' starts the next word
+        let acronym_end = if end < chars.len() && chars[end].is_lowercase() {
+            end - 1
+        } else {
+            end
+        };
+        if acronym_end - start >= 2 {
+            let acronym: String = chars[start..acronym_end].iter().collect();
+            acronyms.push(acronym.to_lowercase());
+        }
+        start = end;
+    }
+    acronyms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let convert = |variant: CaseVariant| variant.convert("foo_bar");
+        assert_eq!(convert(CaseVariant::Ada), "Foo_Bar");
+        assert_eq!(convert(CaseVariant::Dot), "foo.bar");
+        assert_eq!(convert(CaseVariant::Path), "foo/bar");
+        assert_eq!(convert(CaseVariant::Title), "Foo Bar");
+        assert_eq!(convert(CaseVariant::Space), "foo bar");
+        assert_eq!(convert(CaseVariant::Flat), "foobar");
+        assert_eq!(convert(CaseVariant::UpperFlat), "FOOBAR");
+    }
+
+    #[test]
+    fn test_items() {
+        let options = CaseOptions::with_variants(&[CaseVariant::Snake, CaseVariant::Dot]);
+        let items = options.items("FooBar", "SpamEggs");
+        assert_eq!(
+            items,
+            [
+                ("foo_bar".to_string(), "spam_eggs".to_string()),
+                ("foo.bar".to_string(), "spam.eggs".to_string()),
+            ]
+        );
+        assert_eq!(CaseOptions::default().items("foo", "bar").len(), 3);
+    }
+
+    fn replace_all(options: &CaseOptions, pattern: &str, replacement: &str, input: &str) -> String {
+        let query = crate::Query::preserve_case_with(pattern, replacement, options.clone());
+        crate::replace(input, &query)
+            .unwrap()
+            .unwrap()
+            .output()
+            .to_string()
+    }
+
+    #[test]
+    fn test_acronyms() {
+        let options = CaseOptions::default();
+        assert_eq!(
+            replace_all(
+                &options,
+                "http_server",
+                "grpc_server",
+                "HTTPServer httpServer HTTP_SERVER HttpServer"
+            ),
+            "GRPCServer grpcServer GRPC_SERVER GrpcServer"
+        );
+        assert_eq!(
+            replace_all(
+                &options,
+                "HTTPServer",
+                "WebServer",
+                "HTTPServer http_server"
+            ),
+            "WebServer web_server"
+        );
+        assert_eq!(
+            replace_all(
+                &options,
+                "http_server",
+                "web_server",
+                "HTTPServer httpServer HTTP_SERVER"
+            ),
+            "WebServer webServer WEB_SERVER"
+        );
+
+        let options = CaseOptions::default().with_acronyms(&["Foo".to_string(), "Baz".to_string()]);
+        assert_eq!(
+            replace_all(&options, "foo_bar", "baz_qux", "FOOBar FooBar"),
+            "BAZQux BazQux"
+        );
+    }
+
+    #[test]
+    fn test_pluralize() {
+        assert_eq!(pluralize("category"), "categories");
+        assert_eq!(pluralize("FooCategory"), "FooCategories");
+        assert_eq!(pluralize("FOO_BOX"), "FOO_BOXES");
+        assert_eq!(pluralize("http_server"), "http_servers");
+        assert_eq!(pluralize("HTTPServer"), "HTTPServers");
+        assert_eq!(pluralize("Person"), "People");
+    }
+
+    #[test]
+    fn test_plurals() {
+        let options = CaseOptions::default().with_plurals(true);
+        assert_eq!(
+            replace_all(
+                &options,
+                "category",
+                "group",
+                "categories Categories CATEGORIES category"
+            ),
+            "groups Groups GROUPS group"
+        );
+        assert_eq!(
+            replace_all(&options, "box", "item", "boxes box"),
+            "items item"
+        );
+    }
+
+    #[test]
+    fn test_convert_like() {
+        let options = CaseOptions::default();
+        let convert_like = |text, replacement| options.convert_like(text, replacement, false);
+        assert_eq!(convert_like("FooBar", "spam_eggs").unwrap(), "SpamEggs");
+        assert_eq!(convert_like("FOO_BAR", "spam_eggs").unwrap(), "SPAM_EGGS");
+        assert_eq!(convert_like("foo-bar", "spam_Eggs").unwrap(), "spam-eggs");
+        assert_eq!(
+            convert_like("HTTPServer", "grpc_server").unwrap(),
+            "GRPCServer"
+        );
+        assert!(convert_like("foo bar", "spam_eggs").is_none());
+        assert!(options.convert_like("foobar", "spam_eggs", true).is_none());
+        assert_eq!(options.separators_regex(), "(?:\\-|_)?");
+
+        let options = CaseOptions::with_variants(&[CaseVariant::Snake, CaseVariant::Flat]);
+        assert_eq!(
+            options.convert_like("foobar", "spam_eggs", true).unwrap(),
+            "spameggs"
+        );
+        assert_eq!(options.separators_regex(), "(?:_)?");
+    }
+
+    #[test]
+    fn test_detect_acronyms() {
+        assert_eq!(detect_acronyms("XMLHttpRequest"), ["xml"]);
+        assert_eq!(detect_acronyms("userID"), ["id"]);
+        assert!(detect_acronyms("HTTP_SERVER").is_empty());
+        assert!(detect_acronyms("FooBar").is_empty());
+    }
+}
@@ -1,6 +1,12 @@
+use anyhow::{Error, Result, anyhow};
 use colored::*;
+use serde_json::json;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
 
-use crate::{replacer::Fragment, Replacement};
+use crate::stats::SkipReason;
+use crate::{Replacement, Stats, replacer::Fragment};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 /// Control how much information ruplacer prints to standard output
@@ -8,19 +14,96 @@ pub enum Verbosity {
     Quiet,
     #[default]
     Normal,
+    Verbose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Control the format of ruplacer's output
+pub enum OutputFormat {
+    /// Colored diffs, meant to be read by humans
+    #[default]
+    Text,
+    /// One JSON object per line, meant to be read by other programs
+    Json,
+    /// A unified diff, suitable for `git apply` or `patch -p1`
+    Diff,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat, Error> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "diff" => Ok(OutputFormat::Diff),
+            _ => Err(anyhow!("Choose between 'text', 'json', or 'diff'")),
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 /// Used to print messages to the console according to a Verbosity
-/// level
+/// level and an OutputFormat
 pub struct Console {
     verbosity: Verbosity,
+    format: OutputFormat,
+    // When set, output is written there instead of standard output
+    buffer: Option<Mutex<String>>,
 }
 
 impl Console {
     /// Create a new console with the given verbosity
     pub fn with_verbosity(verbosity: Verbosity) -> Self {
-        Self { verbosity }
+        Self {
+            verbosity,
+            ..Default::default()
+        }
+    }
+
+    /// Create a new console with the given verbosity and output format
+    pub fn with_format(verbosity: Verbosity, format: OutputFormat) -> Self {
+        Self {
+            verbosity,
+            format,
+            ..Default::default()
+        }
+    }
+
+    /// Create a new console with the same settings, but which keeps
+    /// its output in memory until it is given to `print_buffered`
+    pub(crate) fn buffered(&self) -> Self {
+        Self {
+            verbosity: self.verbosity,
+            format: self.format,
+            buffer: Some(Mutex::new(String::new())),
+        }
+    }
+
+    /// Print everything a buffered console has kept so far
+    pub(crate) fn print_buffered(&self, buffered: Console) {
+        if let Some(buffer) = buffered.buffer {
+            self.write(&buffer.into_inner().unwrap());
+        }
+    }
+
+    /// Whether a buffered console has kept nothing so far
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buffer
+            .as_ref()
+            .is_none_or(|buffer| buffer.lock().unwrap().is_empty())
+    }
+
+    fn write(&self, text: &str) {
+        match &self.buffer {
+            Some(buffer) => buffer.lock().unwrap().push_str(text),
+            None => print!("{text}"),
+        }
+    }
+
+    /// Return the output format used by this console
+    pub fn format(&self) -> OutputFormat {
+        self.format
     }
 
     /// Create a new console with default verbosity
@@ -30,11 +113,143 @@ impl Console {
 
     /// Print a message to the console
     /// (using standard output)
+    /// Note: messages are not shown when using the JSON output format
     pub fn print_message(&self, message: &str) {
+        if matches!(self.verbosity, Verbosity::Quiet) || self.format != OutputFormat::Text {
+            return;
+        }
+        self.write(message);
+    }
+
+    fn print_json(&self, value: serde_json::Value) {
         if matches!(self.verbosity, Verbosity::Quiet) {
             return;
         }
-        print!("{message}");
+        self.write(&format!("{value}\n"));
+    }
+
+    /// Print the replacements made in a group of lines, starting at `lineno`.
+    /// In text format, this is the same as `print_replacement` with a
+    /// `path:lineno` prefix. In JSON format, print one object per fragment.
+    /// In diff format, do nothing: see `print_patch` instead
+    pub(crate) fn print_hunk(&self, path: &Path, lineno: usize, replacement: &Replacement) {
+        match self.format {
+            OutputFormat::Text => {
+                self.print_replacement_at(path, lineno, replacement);
+                return;
+            }
+            OutputFormat::Diff => return,
+            OutputFormat::Json => (),
+        }
+        let input = replacement.input();
+        for (input_fragment, output_fragment) in replacement.fragments() {
+            let line = lineno + input[..input_fragment.index].matches('\n').count();
+            self.print_json(json!({
+                "type": "match",
+                "path": path.to_string_lossy(),
+                "line": line,
+                "input_offset": input_fragment.index,
+                "output_offset": output_fragment.index,
+                "input": input_fragment.text,
+                "output": output_fragment.text,
+                "input_line": input,
+                "output_line": replacement.output(),
+            }));
+        }
+    }
+
+    /// Print a unified diff (this does nothing unless using the diff format)
+    pub(crate) fn print_patch(&self, patch: &str) {
+        if matches!(self.verbosity, Verbosity::Quiet) || self.format != OutputFormat::Diff {
+            return;
+        }
+        self.write(patch);
+    }
+
+    /// Print the new path of a renamed file or directory
+    /// (this does nothing in diff format)
+    pub(crate) fn print_rename(&self, from: &Path, to: &Path) {
+        match self.format {
+            OutputFormat::Text => {
+                let message = format!(
+                    "{} {} -> {}\n",
+                    "Rename".bold(),
+                    from.display().to_string().red(),
+                    to.display().to_string().green()
+                );
+                self.print_message(&message);
+            }
+            OutputFormat::Json => self.print_json(json!({
+                "type": "rename",
+                "path": from.to_string_lossy(),
+                "new_path": to.to_string_lossy(),
+            })),
+            OutputFormat::Diff => (),
+        }
+    }
+
+    /// Print why a file was skipped
+    /// (in text format, only when verbose)
+    pub(crate) fn print_skipped(&self, path: &Path, reason: &SkipReason) {
+        match self.format {
+            OutputFormat::Text => {
+                if self.verbosity != Verbosity::Verbose {
+                    return;
+                }
+                let message = format!("Skipping {}: {}\n", path.display(), reason);
+                self.write(&message.yellow().to_string());
+            }
+            OutputFormat::Json => self.print_json(json!({
+                "type": "skipped",
+                "path": path.to_string_lossy(),
+                "kind": reason.kind(),
+                "reason": reason.to_string(),
+            })),
+            OutputFormat::Diff => (),
+        }
+    }
+
+    /// Print the lines of a file that were left untouched because they are
+    /// in a skipped region (in text format, only when verbose)
+    pub(crate) fn print_skipped_region(&self, path: &Path, first_line: usize, last_line: usize) {
+        match self.format {
+            OutputFormat::Text => {
+                if self.verbosity != Verbosity::Verbose {
+                    return;
+                }
+                let message = format!(
+                    "Skipping {}:{}-{}: inside a skipped region\n",
+                    path.display(),
+                    first_line,
+                    last_line
+                );
+                self.write(&message.yellow().to_string());
+            }
+            OutputFormat::Json => self.print_json(json!({
+                "type": "skipped_region",
+                "path": path.to_string_lossy(),
+                "first_line": first_line,
+                "last_line": last_line,
+            })),
+            OutputFormat::Diff => (),
+        }
+    }
+
+    /// Print the statistics of a run as a JSON object
+    /// (this does nothing in text format)
+    pub(crate) fn print_json_summary(&self, stats: &Stats, dry_run: bool) {
+        if self.format != OutputFormat::Json {
+            return;
+        }
+        self.print_json(json!({
+            "type": "summary",
+            "dry_run": dry_run,
+            "matching_files": stats.matching_files(),
+            "matching_lines": stats.matching_lines(),
+            "total_replacements": stats.total_replacements(),
+            "skipped_files": stats.skipped().len(),
+            "renamed_paths": stats.renamed_paths(),
+        }));
     }
 
     /// Print an error message to the console
@@ -43,12 +258,27 @@ impl Console {
         eprintln!("{error}");
     }
 
-    /// Print the replacement as two lines (red then green)
+    /// Print a question to the console and return the answer of the user,
+    /// or None if standard input was closed.
+    /// Note: the question is shown regardless of the verbosity level
+    pub fn ask(&self, question: &str) -> Result<Option<String>> {
+        print!("{question}");
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        let num_bytes = std::io::stdin().read_line(&mut answer)?;
+        if num_bytes == 0 {
+            return Ok(None);
+        }
+        Ok(Some(answer.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    /// Print the replacement as two lines (red then green), or two groups of
+    /// lines when it spans several lines, each starting with `prefix`
     /// ```
     /// use ruplacer::{Console, Query, replace};
     /// let input = "let foo_bar = FooBar::new();";
     /// let query = Query::preserve_case("foo_bar", "spam_eggs");
-    /// let replacement = replace(input, &query).unwrap();
+    /// let replacement = replace(input, &query).unwrap().unwrap();
     /// let console = Console::new();
     /// console.print_replacement("foo.rs:3", &replacement);
     /// // outputs:
@@ -56,10 +286,27 @@ impl Console {
     /// // foo.rs:3 let spam_eggs = SpamEggs::new()
     /// ```
     pub fn print_replacement(&self, prefix: &str, replacement: &Replacement) {
+        self.print_lines(&|_| prefix.to_string(), replacement);
+    }
+
+    /// Same as `print_replacement`, with a `path:lineno` prefix on each line,
+    /// `lineno` being the number of the first one
+    pub(crate) fn print_replacement_at(
+        &self,
+        path: &Path,
+        lineno: usize,
+        replacement: &Replacement,
+    ) {
+        let prefix = |line| format!("{}:{} ", path.display(), lineno + line);
+        self.print_lines(&prefix, replacement);
+    }
+
+    // `prefix` is called with the index of each line, starting at 0
+    fn print_lines(&self, prefix: &dyn Fn(usize) -> String, replacement: &Replacement) {
         let red_underline = { |x: &str| x.red().underline() };
         let fragments = replacement.fragments();
         let input_fragments = fragments.into_iter().map(|x| &x.0);
-        let red_prefix = format!("{}{}", prefix, "- ".red());
+        let red_prefix = |line| format!("{}{}", prefix(line), "- ".red());
         self.print_fragments(
             &red_prefix,
             red_underline,
@@ -68,7 +315,7 @@ impl Console {
         );
 
         let green_underline = { |x: &str| x.green().underline() };
-        let green_prefix = format!("{}{}", prefix, "+ ".green());
+        let green_prefix = |line| format!("{}{}", prefix(line), "+ ".green());
         let output_fragments = fragments.into_iter().map(|x| &x.1);
         self.print_fragments(
             &green_prefix,
@@ -80,28 +327,53 @@ impl Console {
 
     fn print_fragments<'f, C>(
         &self,
-        prefix: &str,
+        prefix: &dyn Fn(usize) -> String,
         color: C,
-        line: &str,
+        text: &str,
         fragments: impl Iterator<Item = &'f Fragment>,
     ) where
         C: Fn(&str) -> ColoredString,
     {
-        self.print_message(prefix);
+        let mut line = 0;
+        self.print_message(&prefix(line));
+        // Print text[start..end], with a new prefix after each line
+        // delimiter, except the last one
+        let mut print_part = |start: usize, end: usize, colored: bool| {
+            let mut pos = start;
+            for piece in text[start..end].split_inclusive('\n') {
+                pos += piece.len();
+                let content = piece.strip_suffix('\n').unwrap_or(piece);
+                if colored && !content.is_empty() {
+                    self.print_message(&format!("{}", color(content)));
+                } else {
+                    self.print_message(content);
+                }
+                if content.len() < piece.len() {
+                    self.print_message("\n");
+                    if pos < text.len() {
+                        line += 1;
+                        self.print_message(&prefix(line));
+                    }
+                }
+            }
+        };
         let mut current_index = 0;
         for (i, fragment) in fragments.enumerate() {
-            let Fragment { index, text } = fragment;
+            let Fragment {
+                index,
+                text: fragment_text,
+            } = fragment;
             // Whitespace between prefix and the first fragment does not matter
             if i == 0 {
-                self.print_message((&line[current_index..*index].trim_start()).as_ref());
-            } else {
-                self.print_message(&line[current_index..*index]);
+                let before = &text[current_index..*index];
+                current_index += before.len() - before.trim_start().len();
             }
-            self.print_message(&format!("{}", color(text)));
-            current_index = index + text.len();
+            print_part(current_index, *index, false);
+            print_part(*index, index + fragment_text.len(), true);
+            current_index = index + fragment_text.len();
         }
-        self.print_message(&line[current_index..]);
-        if !line.ends_with('\n') {
+        print_part(current_index, text.len(), false);
+        if !text.ends_with('\n') {
             self.print_message("\n");
         }
     }
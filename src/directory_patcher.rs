@@ -1,11 +1,20 @@
-use anyhow::{Context, Result};
-use std::path::Path;
+use anyhow::{Context, Result, bail};
+use ignore::WalkState;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
 
-use crate::console::Console;
-use crate::file_patcher::FilePatcher;
+use crate::console::{Console, OutputFormat, Verbosity};
+use crate::file_patcher::{FilePatcher, Outcome};
+use crate::git;
+use crate::journal::{self, Journal};
+use crate::plan::Plan;
 use crate::query::Query;
+use crate::replace;
+use crate::rules::Rule;
 use crate::settings::Settings;
-use crate::stats::Stats;
+use crate::stats::{SkipReason, Stats};
 
 #[derive(Debug)]
 /// Used to run replacement query on every text file present in a given path
@@ -33,6 +42,16 @@ pub struct DirectoryPatcher<'a> {
     settings: &'a Settings,
     console: &'a Console,
     stats: Stats,
+    // Set when the user chose to quit in interactive mode
+    quit: bool,
+    // Unified diff of the changes made to each file, written to settings.patch_file
+    patches: Vec<(PathBuf, String)>,
+    // Created before writing the first file, when settings.history_dir is set
+    journal: Option<Journal>,
+    // Replacements of all the files, written to settings.plan_file
+    plan: Plan,
+    // Computed when writing the first unified diff, see diff_root()
+    diff_root: Option<PathBuf>,
 }
 
 impl<'a> DirectoryPatcher<'a> {
@@ -47,20 +66,78 @@ impl<'a> DirectoryPatcher<'a> {
             path,
             settings,
             stats,
+            quit: false,
+            patches: vec![],
+            journal: None,
+            plan: Plan::new(),
+            diff_root: None,
         }
     }
 
     /// Run the given query on the selected files in self.path
     pub fn run(&mut self, query: &Query) -> Result<()> {
-        let walker = self.build_walker()?;
-        for entry in walker {
-            let entry = entry.with_context(|| "Could not read directory entry")?;
-            if let Some(file_type) = entry.file_type() {
-                if file_type.is_file() {
-                    self.patch_file(entry.path(), query)?;
+        self.run_rules(&[Rule::new(query.clone())])
+    }
+
+    /// Run the given rules on the selected files in self.path, during
+    /// a single walk. For each file, the rules are applied in order, each
+    /// rule being applied to the output of the previous one
+    pub fn run_rules(&mut self, rules: &[Rule]) -> Result<()> {
+        // Note: look for collisions before patching any file
+        let renames = match self.settings.rename_paths {
+            true => self.find_renames(rules)?,
+            false => vec![],
+        };
+        let dirty_files = match !self.settings.dry_run && !self.settings.allow_dirty {
+            true => git::dirty_files(self.path)?.filter(|files| !files.is_empty()),
+            false => None,
+        };
+        // Note: when some files have uncommitted changes, make sure none
+        // of them would be changed before writing anything
+        let mut outcomes = dirty_files.as_ref().map(|_| vec![]);
+        // Note: interactive mode needs to ask questions in the order
+        // the files are found, so it can't use several threads
+        if self.settings.interactive || self.settings.threads == 1 {
+            self.walk_sequential(rules, outcomes.as_mut())?;
+        } else {
+            self.walk_parallel(rules, outcomes.as_mut())?;
+        }
+        if let (Some(mut outcomes), Some(dirty_files)) = (outcomes, &dirty_files) {
+            check_clean(&outcomes, &renames, dirty_files)?;
+            outcomes.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+            for (path, outcome, console) in outcomes {
+                if self.quit {
+                    break;
                 }
+                let out = match self.settings.interactive {
+                    true => self.console,
+                    false => &console,
+                };
+                self.handle(out, &path, outcome, rules)?;
+                self.console.print_buffered(console);
             }
         }
+        self.rename_paths(&renames)?;
+        if let Some(patch_file) = &self.settings.patch_file {
+            // Note: in parallel mode, the files are patched in any order
+            self.patches.sort();
+            let patch: String = self
+                .patches
+                .iter()
+                .map(|(_, patch)| patch.as_str())
+                .collect();
+            std::fs::write(patch_file, patch)
+                .with_context(|| format!("Could not write {}", patch_file.display()))?;
+        }
+        if let Some(plan_file) = &self.settings.plan_file {
+            self.plan.save(plan_file)?;
+        }
+        if let Some(journal) = &self.journal {
+            self.console.print_message(&format!(
+                "Original contents saved as run {}. Use `ruplacer undo` to restore them\n",
+                journal.run_id()
+            ));
+        }
         Ok(())
     }
 
@@ -68,54 +145,351 @@ impl<'a> DirectoryPatcher<'a> {
         self.stats
     }
 
-    pub(crate) fn patch_file(&mut self, entry: &Path, query: &Query) -> Result<()> {
-        let file_patcher = FilePatcher::new(self.console, entry, query)?;
-        let file_patcher = match file_patcher {
-            None => return Ok(()),
-            Some(f) => f,
+    // Patch the files one by one, in the order of the walk. When given,
+    // `outcomes` keeps the outcomes instead, to handle them later
+    fn walk_sequential(
+        &mut self,
+        rules: &[Rule],
+        mut outcomes: Option<&mut Outcomes>,
+    ) -> Result<()> {
+        // Note: sort the entries, so that the output is the same as in parallel mode
+        let walker = self
+            .build_walker()?
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+        for entry in walker {
+            if self.quit {
+                break;
+            }
+            let entry = entry.with_context(|| "Could not read directory entry")?;
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            let path = entry.path();
+            // In interactive mode, replacements are shown one by one in review()
+            let quiet_console = Console::with_verbosity(Verbosity::Quiet);
+            match &mut outcomes {
+                Some(outcomes) => {
+                    let console = match self.settings.interactive {
+                        true => quiet_console,
+                        false => self.console.buffered(),
+                    };
+                    let outcome = build_file_patcher(&console, path, rules, self.settings)?;
+                    outcomes.push((entry.into_path(), outcome, console));
+                }
+                None => {
+                    let console = match self.settings.interactive {
+                        true => &quiet_console,
+                        false => self.console,
+                    };
+                    let outcome = build_file_patcher(console, path, rules, self.settings)?;
+                    self.handle(self.console, path, outcome, rules)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Each thread of the walk patches files using its own buffered console,
+    // and sends the outcome to this thread, which handles it right away.
+    // What was printed for each file is kept, and printed sorted by path
+    // at the end, so that the output does not depend on the order in which
+    // the files were processed. When given, `outcomes` keeps the outcomes
+    // instead, to handle them later
+    fn walk_parallel(&mut self, rules: &[Rule], mut outcomes: Option<&mut Outcomes>) -> Result<()> {
+        let walker = self
+            .build_walker()?
+            .threads(self.settings.threads)
+            .build_parallel();
+        let console = self.console;
+        let settings = self.settings;
+        let mut outputs = vec![];
+        let result = thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            scope.spawn(move || {
+                walker.run(|| {
+                    let sender = sender.clone();
+                    Box::new(move |entry| {
+                        let entry = match entry.with_context(|| "Could not read directory entry") {
+                            Ok(entry) => entry,
+                            Err(e) => {
+                                let _ = sender.send((PathBuf::new(), Err(e)));
+                                return WalkState::Quit;
+                            }
+                        };
+                        if !entry.file_type().is_some_and(|t| t.is_file()) {
+                            return WalkState::Continue;
+                        }
+                        let buffered = console.buffered();
+                        let result = build_file_patcher(&buffered, entry.path(), rules, settings)
+                            .map(|outcome| (outcome, buffered));
+                        let state = match result {
+                            Ok(_) => WalkState::Continue,
+                            Err(_) => WalkState::Quit,
+                        };
+                        // Note: sending fails when this thread stopped on an error
+                        match sender.send((entry.into_path(), result)) {
+                            Ok(()) => state,
+                            Err(_) => WalkState::Quit,
+                        }
+                    })
+                })
+            });
+            for (path, result) in receiver {
+                let (outcome, buffered) = result?;
+                match &mut outcomes {
+                    Some(outcomes) => outcomes.push((path, outcome, buffered)),
+                    None => {
+                        self.handle(&buffered, &path, outcome, rules)?;
+                        if !buffered.is_empty() {
+                            outputs.push((path, buffered));
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+        outputs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (_, buffered) in outputs {
+            self.console.print_buffered(buffered);
+        }
+        result
+    }
+
+    // Review and patch the file, printing what happens to `out`
+    fn handle(
+        &mut self,
+        out: &Console,
+        path: &Path,
+        outcome: Outcome,
+        rules: &[Rule],
+    ) -> Result<()> {
+        let mut file_patcher = match outcome {
+            Outcome::Patched(f) => f,
+            Outcome::Unchanged => return Ok(()),
+            Outcome::Skipped(reason) => {
+                self.skip(out, path, reason);
+                return Ok(());
+            }
         };
+        if self.settings.interactive {
+            self.review(&mut file_patcher)?;
+        }
+        self.process(out, file_patcher, rules)
+    }
+
+    fn skip(&mut self, out: &Console, path: &Path, reason: SkipReason) {
+        out.print_skipped(path, &reason);
+        self.stats.update_skipped(path, reason);
+    }
+
+    // Update stats and patch, then write the file unless in dry-run mode
+    fn process(&mut self, out: &Console, file_patcher: FilePatcher, rules: &[Rule]) -> Result<()> {
         let num_replacements = file_patcher.num_replacements();
-        if num_replacements != 0 {
-            self.console.print_message("\n");
+        if num_replacements == 0 {
+            return Ok(());
+        }
+        out.print_message("\n");
+        if out.format() == OutputFormat::Diff || self.settings.patch_file.is_some() {
+            let root = self.diff_root()?;
+            let patch = file_patcher.unified_diff(self.settings.context_lines.unwrap_or(3), &root);
+            out.print_patch(&patch);
+            self.patches
+                .push((file_patcher.path().to_path_buf(), patch));
+        }
+        if self.settings.plan_file.is_some() {
+            self.plan.push(file_patcher.plan()?);
         }
         let num_lines = file_patcher.num_lines();
         self.stats.update(num_lines, num_replacements);
+        let applied: Vec<_> = applicable_rules(file_patcher.path(), rules).collect();
+        for (query_index, lines, replacements) in file_patcher.stats_by_query() {
+            self.stats
+                .update_rule(applied[query_index], lines, replacements);
+        }
         if self.settings.dry_run {
             return Ok(());
         }
+        if let Some(journal) = self.journal()? {
+            journal.record(file_patcher.path(), &file_patcher.encoded_contents()?)?;
+        }
         file_patcher.run()?;
         Ok(())
     }
 
-    fn build_walker(&self) -> Result<ignore::Walk> {
-        let mut types_builder = ignore::types::TypesBuilder::new();
-        types_builder.add_defaults();
-        let mut count: u32 = 0;
-        for t in &self.settings.selected_file_types {
-            // Check if filter is file type or glob pattern
-            if t.contains('*') {
-                let new_type = format!("type{}", count);
-                // Note: .add(name, glob) only returns error with wrong name, hence unwrap()
-                types_builder.add(&new_type, t).unwrap();
-                types_builder.select(&new_type);
-                count += 1;
-            } else {
-                types_builder.select(t);
-            }
-        }
-        for t in &self.settings.ignored_file_types {
-            // Check if filter is file type or glob pattern
-            if t.contains('*') {
-                let new_type = format!("type{}", count);
-                // Note: .add(name, glob) only returns error with wrong name, hence unwrap()
-                types_builder.add(&new_type, t).unwrap();
-                types_builder.negate(&new_type);
-                count += 1;
-            } else {
-                types_builder.negate(t);
-            }
-        }
-        let types_matcher = types_builder.build()?;
+    // The directory the paths in unified diffs are relative to: the top
+    // level of the git repository, or the walked directory when not in a
+    // git repository
+    fn diff_root(&mut self) -> Result<PathBuf> {
+        if let Some(diff_root) = &self.diff_root {
+            return Ok(diff_root.clone());
+        }
+        let diff_root = match git::top_level(self.path) {
+            Ok(top_level) => top_level,
+            Err(_) => {
+                let dir = match self.path.parent() {
+                    Some(parent) if !self.path.is_dir() => parent,
+                    _ => self.path,
+                };
+                // Note: an empty parent means the current directory
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                };
+                dir.canonicalize()
+                    .with_context(|| format!("Could not canonicalize {}", dir.display()))?
+            }
+        };
+        Ok(self.diff_root.insert(diff_root).clone())
+    }
+
+    // Create the journal on first use, when settings.history_dir is set
+    fn journal(&mut self) -> Result<Option<&mut Journal>> {
+        let Some(history_dir) = &self.settings.history_dir else {
+            return Ok(None);
+        };
+        if self.journal.is_none() {
+            self.journal = Some(Journal::create(history_dir)?);
+        }
+        Ok(self.journal.as_mut())
+    }
+
+    // Find the files and directories whose names match the rules, and
+    // return the new path of each one, deepest paths first. Fail if
+    // any of the new paths is already taken
+    fn find_renames(&self, rules: &[Rule]) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut renames = vec![];
+        for entry in self.build_walker()?.build() {
+            let entry = entry.with_context(|| "Could not read directory entry")?;
+            // Note: the path given on the command line is never renamed
+            if entry.depth() == 0 {
+                continue;
+            }
+            let Some(name) = entry.file_name().to_str() else {
+                continue;
+            };
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            let mut new_name = name.to_string();
+            for rule in rules {
+                // Note: directories have no file type, so all rules apply to them
+                if !is_dir && !rule.applies_to(entry.path()) {
+                    continue;
+                }
+                let replacement = replace(&new_name, rule.query())
+                    .with_context(|| format!("Could not rename {}", entry.path().display()))?;
+                if let Some(replacement) = replacement {
+                    new_name = replacement.output().to_string();
+                }
+            }
+            if new_name == name {
+                continue;
+            }
+            if new_name.is_empty() || new_name == "." || new_name == ".." || new_name.contains('/')
+            {
+                bail!("Can't rename {} to '{}'", entry.path().display(), new_name);
+            }
+            let new_path = entry.path().with_file_name(&new_name);
+            renames.push((entry.into_path(), new_path));
+        }
+
+        let mut targets = HashSet::new();
+        let mut collisions = vec![];
+        for (from, to) in &renames {
+            // Note: renaming a path to itself is fine, for instance when
+            // only the case changes on a case-insensitive file system
+            let taken = to.exists() && to.canonicalize().ok() != from.canonicalize().ok();
+            if taken || !targets.insert(to) {
+                collisions.push(format!("{} -> {}", from.display(), to.display()));
+            }
+        }
+        if !collisions.is_empty() {
+            bail!(
+                "Refusing to rename paths, the following new paths are already taken:\n  {}",
+                collisions.join("\n  ")
+            );
+        }
+
+        // Rename the contents of a directory before the directory itself,
+        // so that the paths of its contents are still valid
+        renames.sort_by_key(|(from, _)| std::cmp::Reverse(from.components().count()));
+        Ok(renames)
+    }
+
+    // Show the renames, and perform them unless in dry-run mode
+    fn rename_paths(&mut self, renames: &[(PathBuf, PathBuf)]) -> Result<()> {
+        for (from, to) in renames {
+            self.console.print_rename(from, to);
+        }
+        self.stats.update_renames(renames.len());
+        if self.settings.dry_run {
+            return Ok(());
+        }
+        for (from, to) in renames {
+            if let Some(journal) = self.journal()? {
+                journal.record_rename(from, to)?;
+            }
+            std::fs::rename(from, to).with_context(|| {
+                format!("Could not rename {} to {}", from.display(), to.display())
+            })?;
+        }
+        Ok(())
+    }
+
+    // Ask the user about each replacement, a bit like `git add --patch` does
+    fn review(&mut self, file_patcher: &mut FilePatcher) -> Result<()> {
+        let console = self.console;
+        let quit = &mut self.quit;
+        let mut accept_all = false;
+        let path = file_patcher.path().to_path_buf();
+        file_patcher.select(|lineno, replacement, output_text| {
+            if *quit {
+                return Ok(None);
+            }
+            if accept_all {
+                return Ok(Some(output_text.to_string()));
+            }
+            console.print_replacement_at(&path, lineno, replacement);
+            loop {
+                let answer = console.ask("Apply this replacement [y,n,a,q,e,?]? ")?;
+                let answer = match answer {
+                    None => "q".to_string(),
+                    Some(a) => a,
+                };
+                match answer.trim() {
+                    "y" => return Ok(Some(output_text.to_string())),
+                    "n" => return Ok(None),
+                    "a" => {
+                        accept_all = true;
+                        return Ok(Some(output_text.to_string()));
+                    }
+                    "q" => {
+                        *quit = true;
+                        return Ok(None);
+                    }
+                    "e" => {
+                        if let Some(text) = console.ask("Replace with: ")? {
+                            return Ok(Some(text));
+                        }
+                    }
+                    _ => console.print_message(
+                        "y - apply this replacement\n\
+                         n - do not apply this replacement\n\
+                         a - apply this replacement and all the remaining ones in the file\n\
+                         q - quit; do not apply this replacement or any of the remaining ones\n\
+                         e - manually edit the replacement text\n\
+                         ? - print help\n",
+                    ),
+                }
+            }
+        })
+    }
+
+    fn build_walker(&self) -> Result<ignore::WalkBuilder> {
+        let types_matcher = build_file_types(
+            &self.settings.selected_file_types,
+            &self.settings.ignored_file_types,
+        )?;
         let mut walk_builder = ignore::WalkBuilder::new(self.path);
         walk_builder.types(types_matcher);
         // Note: the walk_builder configures the "ignore" settings of the Walker,
@@ -126,6 +500,136 @@ impl<'a> DirectoryPatcher<'a> {
         if self.settings.hidden {
             walk_builder.hidden(false);
         }
-        Ok(walk_builder.build())
+        let git_files = git::selected_files(self.path, self.settings)?;
+        let excluded_files = self.settings.excluded_files.clone();
+        walk_builder.filter_entry(move |entry| {
+            // Never patch the files saved by previous runs
+            if entry.file_name() == journal::ROOT_DIR_NAME {
+                return false;
+            }
+            // Note: only canonicalize the paths of the entries with the same
+            // name as an excluded file
+            if excluded_files
+                .iter()
+                .any(|excluded| excluded.file_name() == Some(entry.file_name()))
+                && entry
+                    .path()
+                    .canonicalize()
+                    .is_ok_and(|path| excluded_files.contains(&path))
+            {
+                return false;
+            }
+            match &git_files {
+                Some(git_files) if !entry.file_type().is_some_and(|t| t.is_dir()) => entry
+                    .path()
+                    .canonicalize()
+                    .is_ok_and(|path| git_files.contains(&path)),
+                _ => true,
+            }
+        });
+        Ok(walk_builder)
+    }
+}
+
+// The files of a walk, with their outcome and what was printed while
+// matching them
+type Outcomes = Vec<(PathBuf, Outcome, Console)>;
+
+// Make sure none of the files that would be patched or renamed, nor the
+// files inside the directories that would be renamed, has uncommitted changes
+fn check_clean(
+    outcomes: &[(PathBuf, Outcome, Console)],
+    renames: &[(PathBuf, PathBuf)],
+    dirty_files: &HashSet<PathBuf>,
+) -> Result<()> {
+    let mut would_change = vec![];
+    for (path, outcome, _) in outcomes {
+        let is_dirty = || {
+            path.canonicalize()
+                .is_ok_and(|path| dirty_files.contains(&path))
+        };
+        if let Outcome::Patched(_) = outcome
+            && is_dirty()
+        {
+            would_change.push(path.display().to_string());
+        }
+    }
+    for (from, _) in renames {
+        let is_dirty = from
+            .canonicalize()
+            .is_ok_and(|from| dirty_files.iter().any(|path| path.starts_with(&from)));
+        if is_dirty {
+            would_change.push(from.display().to_string());
+        }
+    }
+    if would_change.is_empty() {
+        return Ok(());
+    }
+    would_change.sort();
+    would_change.dedup();
+    bail!(
+        "Refusing to patch files with uncommitted changes:\n  {}\n\
+         Commit or stash them first, or use --allow-dirty",
+        would_change.join("\n  ")
+    );
+}
+
+// Return the indexes of the rules that apply to the given path
+fn applicable_rules<'r>(path: &'r Path, rules: &'r [Rule]) -> impl Iterator<Item = usize> + 'r {
+    rules
+        .iter()
+        .enumerate()
+        .filter(move |(_, rule)| rule.applies_to(path))
+        .map(|(i, _)| i)
+}
+
+fn build_file_patcher(
+    console: &Console,
+    path: &Path,
+    rules: &[Rule],
+    settings: &Settings,
+) -> Result<Outcome> {
+    let queries: Vec<_> = applicable_rules(path, rules)
+        .map(|i| rules[i].query())
+        .collect();
+    if queries.is_empty() {
+        return Ok(Outcome::Unchanged);
+    }
+    FilePatcher::open(console, path, &queries, settings)
+}
+
+/// Build a matcher for the given file types or glob patterns, as used by the
+/// `--type` and `--type-not` command line options
+pub(crate) fn build_file_types(
+    selected: &[String],
+    ignored: &[String],
+) -> Result<ignore::types::Types> {
+    let mut types_builder = ignore::types::TypesBuilder::new();
+    types_builder.add_defaults();
+    let mut count: u32 = 0;
+    for t in selected {
+        // Check if filter is file type or glob pattern
+        if t.contains('*') {
+            let new_type = format!("type{}", count);
+            // Note: .add(name, glob) only returns error with wrong name, hence unwrap()
+            types_builder.add(&new_type, t).unwrap();
+            types_builder.select(&new_type);
+            count += 1;
+        } else {
+            types_builder.select(t);
+        }
+    }
+    for t in ignored {
+        // Check if filter is file type or glob pattern
+        if t.contains('*') {
+            let new_type = format!("type{}", count);
+            // Note: .add(name, glob) only returns error with wrong name, hence unwrap()
+            types_builder.add(&new_type, t).unwrap();
+            types_builder.negate(&new_type);
+            count += 1;
+        } else {
+            types_builder.negate(t);
+        }
     }
+    Ok(types_builder.build()?)
 }
@@ -0,0 +1,157 @@
+use anyhow::{Result, anyhow, bail};
+use encoding_rs::{DecoderResult, Encoding, UTF_8, UTF_16BE, UTF_16LE};
+
+/// How the contents of a file was encoded, so that it can be written
+/// back the same way
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TextEncoding {
+    encoding: &'static Encoding,
+    bom: bool,
+}
+
+/// The result of decoding the contents of a file
+#[derive(Debug)]
+pub(crate) enum Decoded {
+    Text(String, TextEncoding),
+    /// The contents looks like binary data
+    Binary,
+    /// The contents is not valid in the given encoding, starting at
+    /// the given byte offset
+    Invalid(&'static Encoding, usize),
+}
+
+/// Parse an encoding label, like "latin1" or "utf-16le"
+pub(crate) fn parse_encoding(label: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| anyhow!("Unknown encoding: {label}"))
+}
+
+/// Decode the contents of a file.
+/// A byte order mark always wins; otherwise `encoding` is used, defaulting to UTF-8.
+/// Contents with NUL bytes is considered binary, unless it is UTF-16
+pub(crate) fn decode(bytes: &[u8], encoding: Option<&'static Encoding>) -> Decoded {
+    let (encoding, bom_length) = match Encoding::for_bom(bytes) {
+        Some((encoding, bom_length)) => (encoding, bom_length),
+        None => (encoding.unwrap_or(UTF_8), 0),
+    };
+    let bytes = &bytes[bom_length..];
+    if !is_utf16(encoding) && bytes.contains(&0) {
+        return Decoded::Binary;
+    }
+    let bom = bom_length > 0;
+    match encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+        Some(text) => Decoded::Text(text.into_owned(), TextEncoding { encoding, bom }),
+        None => Decoded::Invalid(encoding, bom_length + invalid_offset(encoding, bytes)),
+    }
+}
+
+// Return the offset of the first malformed sequence in `bytes`
+fn invalid_offset(encoding: &'static Encoding, bytes: &[u8]) -> usize {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let capacity = decoder
+        .max_utf8_buffer_length_without_replacement(bytes.len())
+        .unwrap_or(bytes.len());
+    let mut output = String::with_capacity(capacity);
+    let (result, read) = decoder.decode_to_string_without_replacement(bytes, &mut output, true);
+    match result {
+        DecoderResult::Malformed(bad, consumed) => read - bad as usize - consumed as usize,
+        DecoderResult::InputEmpty | DecoderResult::OutputFull => read,
+    }
+}
+
+impl TextEncoding {
+    /// Name of the encoding, like "UTF-8" or "windows-1252"
+    pub(crate) fn name(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    /// Whether all the characters of `text` can be represented in the encoding
+    pub(crate) fn can_encode(&self, text: &str) -> bool {
+        // Note: UTF-8 and UTF-16 can encode any character
+        is_utf16(self.encoding) || !self.encoding.encode(text).2
+    }
+
+    /// Encode `text`, adding the byte order mark if the original contents had one.
+    /// Fail if some characters can't be represented in the encoding
+    pub(crate) fn encode(&self, text: &str) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        // Note: encoding_rs only decodes UTF-16, so encode it by hand
+        if self.encoding == UTF_16LE {
+            if self.bom {
+                bytes.extend_from_slice(&[0xFF, 0xFE]);
+            }
+            bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        } else if self.encoding == UTF_16BE {
+            if self.bom {
+                bytes.extend_from_slice(&[0xFE, 0xFF]);
+            }
+            bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        } else {
+            if self.bom {
+                bytes.extend_from_slice(&[0xEF, 0xBB, 0xBF]);
+            }
+            let (encoded, _, unmappable) = self.encoding.encode(text);
+            if unmappable {
+                bail!("Some characters can't be encoded as {}", self.name());
+            }
+            bytes.extend_from_slice(&encoded);
+        }
+        Ok(bytes)
+    }
+}
+
+fn is_utf16(encoding: &'static Encoding) -> bool {
+    encoding == UTF_16LE || encoding == UTF_16BE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_text(bytes: &[u8], encoding: Option<&'static Encoding>) -> (String, TextEncoding) {
+        match decode(bytes, encoding) {
+            Decoded::Text(text, encoding) => (text, encoding),
+            other => panic!("Could not decode {bytes:?}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_utf8_with_bom() {
+        let bytes = b"\xEF\xBB\xBFold";
+        let (text, encoding) = decode_text(bytes, None);
+        assert_eq!(text, "old");
+        assert_eq!(encoding.encode("new").unwrap(), b"\xEF\xBB\xBFnew");
+    }
+
+    #[test]
+    fn test_utf16_round_trip() {
+        let bytes = b"\xFF\xFEo\0l\0d\0";
+        let (text, encoding) = decode_text(bytes, None);
+        assert_eq!(text, "old");
+        assert_eq!(encoding.name(), "UTF-16LE");
+        assert_eq!(encoding.encode("old").unwrap(), bytes);
+
+        let utf16be = parse_encoding("utf-16be").unwrap();
+        let (text, encoding) = decode_text(b"\0o\0l\0d", Some(utf16be));
+        assert_eq!(text, "old");
+        assert_eq!(encoding.encode("new").unwrap(), b"\0n\0e\0w");
+    }
+
+    #[test]
+    fn test_latin1() {
+        let bytes = b"caf\xe9";
+        assert!(matches!(decode(bytes, None), Decoded::Invalid(_, 3)));
+
+        let latin1 = parse_encoding("latin1").unwrap();
+        let (text, encoding) = decode_text(bytes, Some(latin1));
+        assert_eq!(text, "café");
+        assert_eq!(encoding.encode("thé").unwrap(), b"th\xe9");
+        assert!(encoding.encode("→").is_err());
+        assert!(encoding.can_encode("thé"));
+        assert!(!encoding.can_encode("→"));
+    }
+
+    #[test]
+    fn test_binary() {
+        assert!(matches!(decode(b"\x7fELF\0\0", None), Decoded::Binary));
+    }
+}
@@ -1,135 +1,403 @@
 use anyhow::{Context, Result};
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
+use crate::atomic;
+use crate::encoding::{self, Decoded, TextEncoding};
+use crate::journal;
+use crate::plan::{FilePlan, PlanFragment, PlanHunk};
 use crate::query::Query;
-use crate::replace;
-use crate::Console;
+use crate::replacer::{Fragments, Replacement, get_fragments, get_fragments_by_line, get_output};
+use crate::stats::SkipReason;
+use crate::{Console, Settings};
+
+// Files containing one of these markers in their first lines are
+// considered generated
+const GENERATED_MARKERS: &[&str] = &["@generated", "DO NOT EDIT"];
+const GENERATED_MARKER_LINES: usize = 5;
 
 /// Run replacement query on a given file
 ///
 /// Example, assuming the `data.txt` file contains 'This is my old car'
 /// ```rust
 /// use ruplacer::{Console, FilePatcher, Query};
-/// use std::path::PathBuf;
 ///
-/// # std::fs::write("data.txt", "This is my old car.").unwrap();
-/// let file = PathBuf::from("data.txt");
+/// # let tmp_dir = tempfile::tempdir().unwrap();
+/// # let file = tmp_dir.path().join("data.txt");
+/// # std::fs::write(&file, "This is my old car.").unwrap();
 /// let query = Query::simple("old", "new");
 /// let console = Console::new();
 /// let file_patcher = FilePatcher::new(&console, &file, &query).unwrap();
 /// file_patcher.unwrap().run().unwrap();
 ///
-/// let new_contents = std::fs::read_to_string("data.txt").unwrap();
+/// let new_contents = std::fs::read_to_string(&file).unwrap();
 /// assert_eq!(new_contents, "This is my new car.");
 /// ```
 pub struct FilePatcher {
     path: PathBuf,
+    // One step for each query that matched, in order. The contents of each
+    // step is the output of the previous one
+    steps: Vec<Step>,
     new_contents: String,
-    num_replacements: usize,
-    num_lines: usize,
+    // Used to write the new contents the same way the file was encoded
+    encoding: TextEncoding,
+    // Checksum of the bytes the file contained when it was read
+    checksum: String,
+    preserve_mtime: bool,
+}
+
+/// What happened when trying to build a `FilePatcher`
+pub(crate) enum Outcome {
+    Patched(FilePatcher),
+    /// Nothing to replace in the file
+    Unchanged,
+    Skipped(SkipReason),
+}
+
+struct Step {
+    // Index of the query in the list given to `with_queries`
+    query_index: usize,
+    contents: String,
+    fragments: Fragments,
+    // Computed once from the fragments, and used for printing, counting
+    // lines and saving plans
+    hunks: Vec<Hunk>,
+}
+
+// A group of lines touched by the fragments of a step
+struct Hunk {
+    // Line number of the first line, starting at 1
+    lineno: usize,
+    // Byte range of the lines in the contents of the step
+    range: Range<usize>,
+    // The fragments, with indexes relative to the start of the range
+    fragments: Fragments,
 }
 
 impl FilePatcher {
     /// Try and build a `FilePatcher` for the given path and query
     /// Return Ok(None) if there is nothing to replace in the file
     pub fn new(console: &Console, path: &Path, query: &Query) -> Result<Option<FilePatcher>> {
-        let mut num_replacements = 0;
-        let mut num_lines = 0;
-        let file =
-            File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
-        let reader = BufReader::new(file);
-        let mut new_contents = String::new();
-        // Note: not using lines() because we need to preserve the line endings
-        // when writing the file later on
-        for (num, chunk) in LineIterator::new(b'\n', reader).enumerate() {
-            let chunk = chunk.with_context(|| format!("Error while reading {}", path.display()))?;
-            let line = std::str::from_utf8(&chunk);
-            if line.is_err() {
-                return Ok(None);
+        Self::with_settings(console, path, query, &Settings::default())
+    }
+
+    /// Same as `new`, but also honor the relevant parts of the settings
+    /// (for instance, `multiline`)
+    pub fn with_settings(
+        console: &Console,
+        path: &Path,
+        query: &Query,
+        settings: &Settings,
+    ) -> Result<Option<FilePatcher>> {
+        Self::with_queries(console, path, &[query], settings)
+    }
+
+    /// Same as `with_settings`, but run several queries one after the other,
+    /// each query being run on the output of the previous one
+    /// Note: files that can't be patched (binary, undecodable, ...) are
+    /// silently skipped
+    pub fn with_queries(
+        console: &Console,
+        path: &Path,
+        queries: &[&Query],
+        settings: &Settings,
+    ) -> Result<Option<FilePatcher>> {
+        match Self::open(console, path, queries, settings)? {
+            Outcome::Patched(file_patcher) => Ok(Some(file_patcher)),
+            Outcome::Unchanged | Outcome::Skipped(_) => Ok(None),
+        }
+    }
+
+    /// Same as `with_queries`, but also tell why the file was skipped
+    pub(crate) fn open(
+        console: &Console,
+        path: &Path,
+        queries: &[&Query],
+        settings: &Settings,
+    ) -> Result<Outcome> {
+        if !settings.syntax_filter.supports(path) {
+            return Ok(Outcome::Skipped(SkipReason::UnsupportedLanguage));
+        }
+        let bytes = match read_file(path, settings.max_filesize)? {
+            Ok(bytes) => bytes,
+            Err(reason) => return Ok(Outcome::Skipped(reason)),
+        };
+        let (mut contents, encoding) = match encoding::decode(&bytes, settings.encoding) {
+            Decoded::Text(contents, encoding) => (contents, encoding),
+            Decoded::Binary => return Ok(Outcome::Skipped(SkipReason::Binary)),
+            Decoded::Invalid(encoding, offset) => {
+                let encoding = encoding.name().to_string();
+                return Ok(Outcome::Skipped(SkipReason::InvalidEncoding {
+                    encoding,
+                    offset,
+                }));
             }
-            let line = line.unwrap();
-            let replacement = replace(line, query);
-            match replacement {
-                None => new_contents.push_str(line),
-                Some(replacement) => {
-                    num_lines += 1;
-                    num_replacements += replacement.num_fragments();
-                    let lineno = num + 1;
-                    let prefix = format!("{}:{} ", path.display(), lineno);
-                    console.print_replacement(&prefix, &replacement);
-                    let new_line = replacement.output();
-                    new_contents.push_str(new_line);
+        };
+        if settings.skip_generated && is_generated(&contents) {
+            return Ok(Outcome::Skipped(SkipReason::Generated));
+        }
+        for region in settings.line_filter.skipped_regions(&contents) {
+            console.print_skipped_region(path, region.start + 1, region.end);
+        }
+        let mut steps = vec![];
+        for (query_index, query) in queries.iter().enumerate() {
+            // Note: when not in multiline mode, the query is run line by line,
+            // and the line endings are preserved
+            let allowed_lines = settings.line_filter.allowed_lines(&contents);
+            let fragments = if settings.multiline {
+                get_fragments(&contents, query).map(|fragments| match &allowed_lines {
+                    Some(allowed_lines) => fragments.retain_lines(&contents, allowed_lines),
+                    None => fragments,
+                })
+            } else {
+                get_fragments_by_line(&contents, query, allowed_lines.as_deref())
+            };
+            // Note: the regex engine may fail on some inputs, for instance when
+            // the backtracking limit is exceeded, so skip the whole file
+            let fragments = match fragments {
+                Ok(fragments) => fragments,
+                Err(e) => {
+                    let message = e.to_string();
+                    return Ok(Outcome::Skipped(SkipReason::MatchFailed { message }));
                 }
+            };
+            let fragments = settings.syntax_filter.filter(path, &contents, fragments);
+            if fragments.is_empty() {
+                continue;
             }
+            let step = Step::new(query_index, contents, fragments);
+            for (lineno, replacement) in step.replacements() {
+                console.print_hunk(path, lineno, &replacement);
+            }
+            contents = get_output(&step.contents, &step.fragments);
+            steps.push(step);
+        }
+        if steps.is_empty() {
+            return Ok(Outcome::Unchanged);
         }
-        if num_replacements == 0 {
-            Ok(None)
-        } else {
-            Ok(Some(FilePatcher {
-                path: path.to_path_buf(),
-                new_contents,
-                num_lines,
-                num_replacements,
-            }))
+        // Note: check this now rather than when writing, so that the file
+        // is reported as skipped in dry-run mode too
+        if !encoding.can_encode(&contents) {
+            let encoding = encoding.name().to_string();
+            return Ok(Outcome::Skipped(SkipReason::Unencodable { encoding }));
         }
+
+        Ok(Outcome::Patched(FilePatcher {
+            path: path.to_path_buf(),
+            steps,
+            new_contents: contents,
+            encoding,
+            checksum: journal::checksum(&bytes),
+            preserve_mtime: settings.preserve_mtime,
+        }))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
     }
 
     pub(crate) fn num_replacements(&self) -> usize {
-        self.num_replacements
+        self.steps.iter().map(|step| step.fragments.len()).sum()
     }
 
     pub(crate) fn num_lines(&self) -> usize {
-        self.num_lines
+        self.steps.iter().map(Step::num_lines).sum()
+    }
+
+    /// Return the index of each query that matched, along with the number of
+    /// lines it changed and the number of replacements it made
+    pub(crate) fn stats_by_query(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        self.steps
+            .iter()
+            .map(|step| (step.query_index, step.num_lines(), step.fragments.len()))
+    }
+
+    /// Ask `choose` what to do with each replacement, one fragment at a time.
+    /// `choose` is called with the number of the first line of the fragment,
+    /// the replacement for this fragment only and its output text. It returns the text to use
+    /// instead of the fragment, or None to leave the input untouched.
+    /// Note: only the replacements of the last query are reviewed, so this
+    /// is meant to be used with a single query
+    pub(crate) fn select<F>(&mut self, mut choose: F) -> Result<()>
+    where
+        F: FnMut(usize, &Replacement, &str) -> Result<Option<String>>,
+    {
+        let step = self.steps.last_mut().expect("at least one step");
+        let mut choices = vec![];
+        for hunk in &step.hunks {
+            // Note: only look for the lines of each fragment inside its hunk
+            let lines = &step.contents[hunk.range.clone()];
+            for (input_fragment, output_fragment) in &hunk.fragments {
+                let single = Fragments::from((input_fragment.clone(), output_fragment.clone()));
+                for (line, range, fragments) in single.group_by_lines(lines) {
+                    let replacement = Replacement::new(&lines[range], fragments);
+                    choices.push(choose(
+                        hunk.lineno + line,
+                        &replacement,
+                        &output_fragment.text,
+                    )?);
+                }
+            }
+        }
+        let mut choices = choices.into_iter();
+        let fragments = std::mem::take(&mut step.fragments);
+        step.fragments = fragments.filter_map(|_, _| choices.next().flatten());
+        step.hunks = hunks(&step.contents, &step.fragments);
+        self.new_contents = get_output(&step.contents, &step.fragments);
+        Ok(())
+    }
+
+    /// Return the changes as a unified diff, with `context` lines around each
+    /// hunk, suitable for `git apply` or `patch -p1` when run from `root`.
+    /// `root` must be canonical
+    pub(crate) fn unified_diff(&self, context: usize, root: &Path) -> String {
+        let relative_path = self
+            .path
+            .canonicalize()
+            .ok()
+            .and_then(|path| path.strip_prefix(root).ok().map(Path::to_path_buf));
+        let path = match &relative_path {
+            Some(relative_path) => relative_path.to_string_lossy(),
+            None => self.path.to_string_lossy(),
+        };
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        similar::TextDiff::from_lines(self.original_contents(), &self.new_contents)
+            .unified_diff()
+            .context_radius(context)
+            .header(&format!("a/{path}"), &format!("b/{path}"))
+            .to_string()
+    }
+
+    /// Return the replacements, so that they can be saved and applied later,
+    /// from any directory
+    pub(crate) fn plan(&self) -> Result<FilePlan> {
+        let steps = self
+            .steps
+            .iter()
+            .map(|step| {
+                step.hunks
+                    .iter()
+                    .map(|hunk| PlanHunk {
+                        line: hunk.lineno,
+                        fragments: hunk
+                            .fragments
+                            .into_iter()
+                            .map(|(input, output)| PlanFragment {
+                                input_index: input.index,
+                                input: input.text.clone(),
+                                output_index: output.index,
+                                output: output.text.clone(),
+                            })
+                            .collect(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let path = self
+            .path
+            .canonicalize()
+            .with_context(|| format!("Could not canonicalize {}", self.path.display()))?;
+        Ok(FilePlan {
+            path,
+            checksum: self.checksum.clone(),
+            encoding: self.encoding.name().to_string(),
+            steps,
+        })
+    }
+
+    // The contents of the first step is the contents of the file, since
+    // the queries before it did not change anything
+    fn original_contents(&self) -> &str {
+        &self.steps[0].contents
+    }
+
+    /// The new contents, encoded like the original file was
+    pub(crate) fn encoded_contents(&self) -> Result<Vec<u8>> {
+        self.encoding
+            .encode(&self.new_contents)
+            .with_context(|| format!("Could not encode new contents of {}", self.path.display()))
     }
 
     /// Write new contents to the file.
+    /// The file is replaced atomically, keeping its permissions - see
+    /// `atomic::write_file` for the details
     pub fn run(&self) -> Result<()> {
-        std::fs::write(&self.path, &self.new_contents)
-            .with_context(|| format!("Could not write {}", self.path.display()))?;
-        Ok(())
+        let bytes = self.encoded_contents()?;
+        atomic::write_file(&self.path, &bytes, self.preserve_mtime)
     }
 }
 
-/// `LineIterator` wraps `BufRead`'s `read_until` method in an iterator, thereby
-/// preserving the delimiter in the yielded values.
-struct LineIterator<T: BufRead> {
-    delimiter: u8,
-    reader: T,
-}
+impl Step {
+    fn new(query_index: usize, contents: String, fragments: Fragments) -> Self {
+        let hunks = hunks(&contents, &fragments);
+        Self {
+            query_index,
+            contents,
+            fragments,
+            hunks,
+        }
+    }
 
-impl<T: BufRead> LineIterator<T> {
-    fn new(delimiter: u8, reader: T) -> Self {
-        Self { delimiter, reader }
+    // Return the line number and the replacement for each hunk
+    fn replacements(&self) -> impl Iterator<Item = (usize, Replacement<'_>)> {
+        self.hunks.iter().map(|hunk| {
+            let input = &self.contents[hunk.range.clone()];
+            (hunk.lineno, Replacement::new(input, hunk.fragments.clone()))
+        })
+    }
+
+    fn num_lines(&self) -> usize {
+        self.hunks
+            .iter()
+            .map(|hunk| self.contents[hunk.range.clone()].lines().count().max(1))
+            .sum()
     }
 }
 
-impl<T: BufRead> Iterator for LineIterator<T> {
-    type Item = io::Result<Vec<u8>>;
+fn is_generated(contents: &str) -> bool {
+    contents
+        .lines()
+        .take(GENERATED_MARKER_LINES)
+        .any(|line| GENERATED_MARKERS.iter().any(|marker| line.contains(marker)))
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut buf = Vec::new();
-        match self.reader.read_until(self.delimiter, &mut buf) {
-            Ok(0) => None,
-            Ok(_) => Some(Ok(buf)),
-            Err(e) => Some(Err(e)),
+// Read the file, unless it's too big or can't be read because of its permissions
+fn read_file(path: &Path, max_filesize: Option<u64>) -> Result<Result<Vec<u8>, SkipReason>> {
+    let result = std::fs::metadata(path).and_then(|metadata| match max_filesize {
+        Some(max) if metadata.len() > max => Ok(Err(SkipReason::TooLarge {
+            size: metadata.len(),
+        })),
+        _ => std::fs::read(path).map(Ok),
+    });
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Ok(Err(SkipReason::PermissionDenied))
         }
+        result => result.with_context(|| format!("Could not read {}", path.display())),
     }
 }
 
+// Group the fragments by the lines of `contents` they touch
+fn hunks(contents: &str, fragments: &Fragments) -> Vec<Hunk> {
+    fragments
+        .clone()
+        .group_by_lines(contents)
+        .into_iter()
+        .map(|(line, range, fragments)| Hunk {
+            lineno: line + 1,
+            range,
+            fragments,
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::LineFilter;
+    use crate::syntax::{NodeKind, SyntaxFilter};
+    use crate::test_utils::temp_dir;
     use std::fs;
-    use tempfile::TempDir;
-
-    fn temp_dir() -> TempDir {
-        tempfile::Builder::new()
-            .prefix("test-ruplacer")
-            .tempdir()
-            .unwrap()
-    }
 
     #[test]
     fn test_patch_file() {
@@ -154,4 +422,146 @@ mod tests {
         let expected = "first line\nI say: new is nice\nlast line\n";
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_patch_file_multiline() {
+        let temp_dir = temp_dir();
+
+        let file_path = temp_dir.path().join("multiline.txt");
+        fs::write(&file_path, "first line\nfoo(\n    bar)\nlast line\n").unwrap();
+        let regex = regex::RegexBuilder::new(r"foo\(\s*(\w+)\)")
+            .multi_line(true)
+            .build()
+            .unwrap();
+        let query = Query::regex(regex, "foo($1)").unwrap();
+        let settings = Settings {
+            multiline: true,
+            ..Default::default()
+        };
+        let console = Console::new();
+        let file_patcher = FilePatcher::with_settings(&console, &file_path, &query, &settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(file_patcher.num_lines(), 2);
+        file_patcher.run().unwrap();
+        let actual = fs::read_to_string(&file_path).unwrap();
+        let expected = "first line\nfoo(bar)\nlast line\n";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_line_filter() {
+        let temp_dir = temp_dir();
+
+        let file_path = temp_dir.path().join("imports.py");
+        fs::write(&file_path, "import old\nold()\nimport old  # DO NOT EDIT\n").unwrap();
+        let query = Query::simple("old", "new");
+        let line_filter = LineFilter {
+            only: Some(regex::Regex::new("^import").unwrap()),
+            skip: Some(regex::Regex::new("DO NOT EDIT").unwrap()),
+            ..Default::default()
+        };
+        for multiline in [false, true] {
+            let settings = Settings {
+                multiline,
+                line_filter: line_filter.clone(),
+                ..Default::default()
+            };
+            let console = Console::new();
+            let file_patcher = FilePatcher::with_settings(&console, &file_path, &query, &settings)
+                .unwrap()
+                .unwrap();
+            assert_eq!(file_patcher.num_replacements(), 1);
+            assert_eq!(
+                file_patcher.encoded_contents().unwrap(),
+                b"import new\nold()\nimport old  # DO NOT EDIT\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_skip_unsupported_languages() {
+        let temp_dir = temp_dir();
+
+        let file_path = temp_dir.path().join("README.md");
+        fs::write(&file_path, "old\n").unwrap();
+        let query = Query::simple("old", "new");
+        let settings = Settings {
+            syntax_filter: SyntaxFilter {
+                only: vec![NodeKind::Identifiers],
+                skip: vec![],
+            },
+            ..Default::default()
+        };
+        let console = Console::new();
+        let outcome = FilePatcher::open(&console, &file_path, &[&query], &settings).unwrap();
+        assert!(matches!(
+            outcome,
+            Outcome::Skipped(SkipReason::UnsupportedLanguage)
+        ));
+    }
+
+    #[test]
+    fn test_patch_utf16_file() {
+        let temp_dir = temp_dir();
+
+        let file_path = temp_dir.path().join("utf16.txt");
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend("old\r\n".encode_utf16().flat_map(u16::to_le_bytes));
+        fs::write(&file_path, &bytes).unwrap();
+        let query = Query::simple("old", "new");
+        let console = Console::new();
+        let file_patcher = FilePatcher::new(&console, &file_path, &query).unwrap();
+        file_patcher.unwrap().run().unwrap();
+
+        let mut expected = vec![0xFF, 0xFE];
+        expected.extend("new\r\n".encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(fs::read(&file_path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_select_replacements() {
+        let temp_dir = temp_dir();
+
+        let file_path = temp_dir.path().join("select.txt");
+        fs::write(&file_path, "old old\nold\n").unwrap();
+        let query = Query::simple("old", "new");
+        let console = Console::new();
+        let mut file_patcher = FilePatcher::new(&console, &file_path, &query)
+            .unwrap()
+            .unwrap();
+        let mut answers =
+            vec![Some("new".to_string()), None, Some("edited".to_string())].into_iter();
+        file_patcher
+            .select(|_, _, _| Ok(answers.next().unwrap()))
+            .unwrap();
+        assert_eq!(file_patcher.num_replacements(), 2);
+        file_patcher.run().unwrap();
+        let actual = fs::read_to_string(&file_path).unwrap();
+        assert_eq!(actual, "new old\nedited\n");
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let temp_dir = temp_dir();
+
+        let file_path = temp_dir.path().join("diff.txt");
+        fs::write(&file_path, "first line\nI say: old is nice\nlast line").unwrap();
+        let query = Query::simple("old", "new");
+        let console = Console::new();
+        let file_patcher = FilePatcher::new(&console, &file_path, &query)
+            .unwrap()
+            .unwrap();
+        let expected = "--- a/diff.txt
++++ b/diff.txt
+@@ -1,3 +1,3 @@
+ first line
+-I say: old is nice
++I say: new is nice
+ last line
+\\ No newline at end of file
+";
+        let root = temp_dir.path().canonicalize().unwrap();
+        assert_eq!(file_patcher.unified_diff(3, &root), expected);
+    }
 }
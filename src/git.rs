@@ -0,0 +1,199 @@
+use anyhow::{Context, Result, bail};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::Settings;
+
+/// Return the files selected by the `git_tracked` and `changed_since`
+/// settings, as canonical paths, or None if neither is set.
+/// When both are set, only the files matching both are selected
+pub(crate) fn selected_files(path: &Path, settings: &Settings) -> Result<Option<HashSet<PathBuf>>> {
+    let tracked = match settings.git_tracked {
+        true => Some(tracked_files(path)?),
+        false => None,
+    };
+    let changed = match &settings.changed_since {
+        Some(revision) => Some(changed_files(path, revision)?),
+        None => None,
+    };
+    Ok(match (tracked, changed) {
+        (Some(tracked), Some(changed)) => Some(&tracked & &changed),
+        (tracked, changed) => tracked.or(changed),
+    })
+}
+
+/// Files in the index of the repository containing `path`
+pub(crate) fn tracked_files(path: &Path) -> Result<HashSet<PathBuf>> {
+    let top_level = top_level(path)?;
+    list_files(&top_level, &["ls-files", "-z"])
+}
+
+/// Files that differ from `revision`, staged or not, along with the
+/// untracked files (except the ignored ones)
+pub(crate) fn changed_files(path: &Path, revision: &str) -> Result<HashSet<PathBuf>> {
+    let top_level = top_level(path)?;
+    // Note: "--" makes sure the revision is not mistaken for a path
+    let mut files = list_files(
+        &top_level,
+        &["diff", "--name-only", "--no-renames", "-z", revision, "--"],
+    )?;
+    files.extend(list_files(
+        &top_level,
+        &["ls-files", "-z", "--others", "--exclude-standard"],
+    )?);
+    Ok(files)
+}
+
+/// Files with uncommitted changes (staged or not, including untracked files)
+/// in the repository containing `path`, or None if `path` is not in a git repository
+pub(crate) fn dirty_files(path: &Path) -> Result<Option<HashSet<PathBuf>>> {
+    let top_level = match top_level(path) {
+        Ok(top_level) => top_level,
+        Err(_) => return Ok(None),
+    };
+    let output = run_git(
+        &top_level,
+        &["status", "--porcelain", "-z", "--untracked-files=all"],
+    )?;
+    // Each entry looks like "XY path". For renames and copies, the entry
+    // is followed by the original path, which is left untouched
+    let mut files = HashSet::new();
+    let mut entries = output.split('\0').filter(|entry| !entry.is_empty());
+    while let Some(entry) = entries.next() {
+        let (status, name) = entry.split_at(3.min(entry.len()));
+        files.insert(top_level.join(name));
+        if status.starts_with(['R', 'C']) {
+            entries.next();
+        }
+    }
+    Ok(Some(files))
+}
+
+/// The root of the working tree containing `path`
+pub(crate) fn top_level(path: &Path) -> Result<PathBuf> {
+    let dir = if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap_or(Path::new("."))
+    };
+    // Note: an empty parent means the current directory
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let output = run_git(dir, &["rev-parse", "--show-toplevel"])
+        .with_context(|| format!("{} is not in a git repository", path.display()))?;
+    let top_level = PathBuf::from(output.trim_end());
+    top_level
+        .canonicalize()
+        .with_context(|| format!("Could not canonicalize {}", top_level.display()))
+}
+
+// Run a git command listing paths relative to `top_level`, separated by NUL bytes
+fn list_files(top_level: &Path, args: &[&str]) -> Result<HashSet<PathBuf>> {
+    let output = run_git(top_level, args)?;
+    Ok(output
+        .split('\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| top_level.join(name))
+        .collect())
+}
+
+pub(crate) fn run_git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("Could not run git")?;
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    String::from_utf8(output.stdout).context("git output is not valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let args: Vec<_> = ["-c", "user.name=test", "-c", "user.email=test@example.com"]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect();
+        run_git(dir, &args).unwrap();
+    }
+
+    fn setup_repo() -> TempDir {
+        let temp_dir = temp_dir();
+        let path = temp_dir.path();
+        git(path, &["init", "--quiet"]);
+        fs::write(path.join("committed.txt"), "old").unwrap();
+        fs::write(path.join("modified.txt"), "old").unwrap();
+        git(path, &["add", "."]);
+        git(path, &["commit", "--quiet", "-m", "initial commit"]);
+        fs::write(path.join("modified.txt"), "old old").unwrap();
+        fs::write(path.join("untracked.txt"), "old").unwrap();
+        temp_dir
+    }
+
+    fn names(files: &HashSet<PathBuf>) -> Vec<String> {
+        let mut names: Vec<_> = files
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_tracked_files() {
+        let temp_dir = setup_repo();
+        let files = tracked_files(temp_dir.path()).unwrap();
+        assert_eq!(names(&files), ["committed.txt", "modified.txt"]);
+    }
+
+    #[test]
+    fn test_changed_files() {
+        let temp_dir = setup_repo();
+        let files = changed_files(temp_dir.path(), "HEAD").unwrap();
+        assert_eq!(names(&files), ["modified.txt", "untracked.txt"]);
+
+        let settings = Settings {
+            git_tracked: true,
+            changed_since: Some("HEAD".to_string()),
+            ..Default::default()
+        };
+        let files = selected_files(temp_dir.path(), &settings).unwrap().unwrap();
+        assert_eq!(names(&files), ["modified.txt"]);
+    }
+
+    #[test]
+    fn test_dirty_files() {
+        let temp_dir = setup_repo();
+        let path = temp_dir.path();
+        git(path, &["mv", "committed.txt", "renamed.txt"]);
+        let files = dirty_files(path).unwrap().unwrap();
+        assert_eq!(
+            names(&files),
+            ["modified.txt", "renamed.txt", "untracked.txt"]
+        );
+    }
+
+    #[test]
+    fn test_not_a_repository() {
+        let temp_dir = temp_dir();
+        assert!(tracked_files(temp_dir.path()).is_err());
+        assert!(dirty_files(temp_dir.path()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,388 @@
+use crate::atomic;
+use crate::git;
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the directory where ruplacer keeps its own files.
+/// It is never walked
+pub(crate) const ROOT_DIR_NAME: &str = ".ruplacer";
+
+const JOURNAL_FILE: &str = "journal.jsonl";
+
+/// Number of runs kept in the history directory. Older runs are removed
+/// when a new one starts
+const MAX_RUNS: usize = 20;
+
+/// The directory where the history of the runs is kept by default:
+/// at the top level of the git repository containing `path`, or next
+/// to `path` itself when it is not in a git repository
+pub(crate) fn default_history_dir(path: &Path) -> PathBuf {
+    let root = git::top_level(path).unwrap_or_else(|_| match path.parent() {
+        Some(parent) if !path.is_dir() => parent.to_path_buf(),
+        _ => path.to_path_buf(),
+    });
+    root.join(ROOT_DIR_NAME).join("history")
+}
+
+/// Record the original contents of the files patched during a run,
+/// so that the run can be undone later.
+///
+/// Each run gets its own directory inside the history directory, named
+/// after the time the run started. It contains a copy of each original
+/// file, and a `journal.jsonl` file with one entry per patched file or
+/// renamed path. Only the latest runs are kept.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    run_id: String,
+    dir: PathBuf,
+    num_entries: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Patch(Patch),
+    Rename(Rename),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Patch {
+    path: PathBuf,
+    backup: String,
+    original_checksum: String,
+    new_checksum: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Rename {
+    renamed_from: PathBuf,
+    renamed_to: PathBuf,
+}
+
+pub(crate) fn checksum(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+impl Journal {
+    /// Create a new run directory in `history_dir`
+    pub(crate) fn create(history_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(history_dir)
+            .with_context(|| format!("Could not create {}", history_dir.display()))?;
+        // Note: the history is usually inside a git repository, where it
+        // should not show up as untracked files
+        let gitignore_path = history_dir.join(".gitignore");
+        if !gitignore_path.exists() {
+            fs::write(&gitignore_path, "*\n")
+                .with_context(|| format!("Could not write {}", gitignore_path.display()))?;
+        }
+        let mut millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // Note: two runs may start during the same millisecond, so use
+        // the next free id rather than sharing a directory
+        loop {
+            let run_id = millis.to_string();
+            let dir = history_dir.join(&run_id);
+            match fs::create_dir(&dir) {
+                Ok(()) => {
+                    remove_old_runs(history_dir);
+                    return Ok(Self {
+                        run_id,
+                        dir,
+                        num_entries: 0,
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => millis += 1,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Could not create {}", dir.display()));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Save the current contents of `path`, before `new_contents` is written to it
+    pub(crate) fn record(&mut self, path: &Path, new_contents: &[u8]) -> Result<()> {
+        let original =
+            fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+        let backup = format!("{}.orig", self.num_entries);
+        let backup_path = self.dir.join(&backup);
+        fs::write(&backup_path, &original)
+            .with_context(|| format!("Could not write {}", backup_path.display()))?;
+        let entry = Entry::Patch(Patch {
+            path: path
+                .canonicalize()
+                .with_context(|| format!("Could not canonicalize {}", path.display()))?,
+            backup,
+            original_checksum: checksum(&original),
+            new_checksum: checksum(new_contents),
+        });
+        self.append(&entry)
+    }
+
+    /// Record that `from` is about to be renamed to `to`, which must be
+    /// in the same directory
+    pub(crate) fn record_rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let renamed_from = from
+            .canonicalize()
+            .with_context(|| format!("Could not canonicalize {}", from.display()))?;
+        let renamed_to = match (renamed_from.parent(), to.file_name()) {
+            (Some(parent), Some(name)) => parent.join(name),
+            _ => bail!("Can't rename {} to {}", from.display(), to.display()),
+        };
+        self.append(&Entry::Rename(Rename {
+            renamed_from,
+            renamed_to,
+        }))
+    }
+
+    // Note: entries are appended one by one, so that the journal
+    // stays usable even if the run is interrupted
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let journal_path = self.dir.join(JOURNAL_FILE);
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .with_context(|| format!("Could not open {}", journal_path.display()))?;
+        writeln!(journal, "{}", serde_json::to_string(&entry)?)
+            .with_context(|| format!("Could not write {}", journal_path.display()))?;
+        self.num_entries += 1;
+        Ok(())
+    }
+}
+
+/// Restore the files patched and the paths renamed during the given run
+/// (or the latest one), and return the run id along with the number of
+/// reverted changes.
+///
+/// Nothing is restored if any of the files was changed since the run
+pub(crate) fn undo(history_dir: &Path, run_id: Option<&str>) -> Result<(String, usize)> {
+    let run_id = match run_id {
+        Some(run_id) => run_id.to_string(),
+        None => latest_run_id(history_dir)?,
+    };
+    let dir = history_dir.join(&run_id);
+    let journal_path = dir.join(JOURNAL_FILE);
+    let journal = fs::read_to_string(&journal_path)
+        .with_context(|| format!("Could not read journal for run {run_id}"))?;
+    let entries = journal
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Entry>, _>>()
+        .with_context(|| format!("Could not parse {}", journal_path.display()))?;
+
+    // Where a path is now, taking into account the renames made after
+    // the entry at `index`
+    let current_path = |index: usize, path: &Path| {
+        let mut path = path.to_path_buf();
+        for entry in &entries[index + 1..] {
+            if let Entry::Rename(rename) = entry
+                && let Ok(rest) = path.strip_prefix(&rename.renamed_from)
+            {
+                path = match rest.as_os_str().is_empty() {
+                    true => rename.renamed_to.clone(),
+                    false => rename.renamed_to.join(rest),
+                };
+            }
+        }
+        path
+    };
+
+    let mut changed = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        match entry {
+            Entry::Patch(patch) => {
+                let path = current_path(index, &patch.path);
+                let current = fs::read(&path).unwrap_or_default();
+                if checksum(&current) != patch.new_checksum {
+                    changed.push(path.display().to_string());
+                }
+            }
+            Entry::Rename(rename) => {
+                let path = current_path(index, &rename.renamed_to);
+                if !path.exists() {
+                    changed.push(path.display().to_string());
+                }
+            }
+        }
+    }
+    if !changed.is_empty() {
+        bail!(
+            "Refusing to undo run {run_id}: the following files have changed since:\n  {}",
+            changed.join("\n  ")
+        );
+    }
+
+    // Note: restore in reverse order, in case the same file was patched twice,
+    // and so that each path is back where it was when its entry was recorded
+    for entry in entries.iter().rev() {
+        match entry {
+            Entry::Patch(patch) => restore(&dir, patch)?,
+            Entry::Rename(rename) => fs::rename(&rename.renamed_to, &rename.renamed_from)
+                .with_context(|| {
+                    format!(
+                        "Could not rename {} to {}",
+                        rename.renamed_to.display(),
+                        rename.renamed_from.display()
+                    )
+                })?,
+        }
+    }
+    fs::remove_dir_all(&dir).with_context(|| format!("Could not remove {}", dir.display()))?;
+    Ok((run_id, entries.len()))
+}
+
+fn restore(dir: &Path, patch: &Patch) -> Result<()> {
+    let backup_path = dir.join(&patch.backup);
+    let original = fs::read(&backup_path)
+        .with_context(|| format!("Could not read {}", backup_path.display()))?;
+    if checksum(&original) != patch.original_checksum {
+        bail!("Backup of {} is corrupted", patch.path.display());
+    }
+    atomic::write_file(&patch.path, &original, false)
+}
+
+fn latest_run_id(history_dir: &Path) -> Result<String> {
+    let run_ids = run_ids(history_dir).map_err(|_| anyhow!("No run to undo"))?;
+    run_ids
+        .last()
+        .map(|millis| millis.to_string())
+        .ok_or_else(|| anyhow!("No run to undo"))
+}
+
+// Note: failing to remove an old run must not prevent patching files,
+// so errors are ignored here
+fn remove_old_runs(history_dir: &Path) {
+    let Ok(run_ids) = run_ids(history_dir) else {
+        return;
+    };
+    let num_old_runs = run_ids.len().saturating_sub(MAX_RUNS);
+    for millis in &run_ids[..num_old_runs] {
+        let _ = fs::remove_dir_all(history_dir.join(millis.to_string()));
+    }
+}
+
+// The ids of the runs in `history_dir`, oldest first
+fn run_ids(history_dir: &Path) -> Result<Vec<u128>> {
+    let mut run_ids = vec![];
+    for entry in fs::read_dir(history_dir)? {
+        let entry = entry?;
+        if let Ok(millis) = entry.file_name().to_string_lossy().parse::<u128>() {
+            run_ids.push(millis);
+        }
+    }
+    run_ids.sort_unstable();
+    Ok(run_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{temp_dir, write_file};
+
+    #[test]
+    fn test_record_and_undo() {
+        let temp_dir = temp_dir();
+        let history_dir = temp_dir.path().join("history");
+        let file_path = write_file(temp_dir.path(), "foo.txt", "old");
+
+        let mut journal = Journal::create(&history_dir).unwrap();
+        journal.record(&file_path, b"new").unwrap();
+        fs::write(&file_path, "new").unwrap();
+
+        let (run_id, num_files) = undo(&history_dir, None).unwrap();
+        assert_eq!(run_id, journal.run_id());
+        assert_eq!(num_files, 1);
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "old");
+        assert!(!history_dir.join(run_id).exists());
+    }
+
+    #[test]
+    fn test_undo_renames() {
+        let temp_dir = temp_dir();
+        let history_dir = temp_dir.path().join("history");
+        let old_dir = temp_dir.path().join("old_dir");
+        fs::create_dir(&old_dir).unwrap();
+        let file_path = write_file(&old_dir, "old.txt", "old");
+
+        let mut journal = Journal::create(&history_dir).unwrap();
+        journal.record(&file_path, b"new").unwrap();
+        fs::write(&file_path, "new").unwrap();
+        let new_file_path = old_dir.join("new.txt");
+        journal.record_rename(&file_path, &new_file_path).unwrap();
+        fs::rename(&file_path, &new_file_path).unwrap();
+        let new_dir = temp_dir.path().join("new_dir");
+        journal.record_rename(&old_dir, &new_dir).unwrap();
+        fs::rename(&old_dir, &new_dir).unwrap();
+
+        let (_, num_entries) = undo(&history_dir, None).unwrap();
+        assert_eq!(num_entries, 3);
+        assert!(!new_dir.exists());
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_run_ids_are_unique() {
+        let temp_dir = temp_dir();
+        let history_dir = temp_dir.path().join("history");
+
+        let first = Journal::create(&history_dir).unwrap();
+        let second = Journal::create(&history_dir).unwrap();
+        assert_ne!(first.run_id(), second.run_id());
+        assert_eq!(latest_run_id(&history_dir).unwrap(), second.run_id());
+    }
+
+    #[test]
+    fn test_only_the_latest_runs_are_kept() {
+        let temp_dir = temp_dir();
+        let history_dir = temp_dir.path().join("history");
+
+        let first = Journal::create(&history_dir).unwrap();
+        let mut last = None;
+        for _ in 0..MAX_RUNS {
+            last = Some(Journal::create(&history_dir).unwrap());
+        }
+        assert_eq!(run_ids(&history_dir).unwrap().len(), MAX_RUNS);
+        assert!(!history_dir.join(first.run_id()).exists());
+        assert_eq!(latest_run_id(&history_dir).unwrap(), last.unwrap().run_id());
+        assert_eq!(
+            fs::read_to_string(history_dir.join(".gitignore")).unwrap(),
+            "*\n"
+        );
+    }
+
+    #[test]
+    fn test_default_history_dir_outside_a_repository() {
+        let temp_dir = temp_dir();
+        let file_path = write_file(temp_dir.path(), "foo.txt", "foo");
+
+        let expected = temp_dir.path().join(ROOT_DIR_NAME).join("history");
+        assert_eq!(default_history_dir(temp_dir.path()), expected);
+        assert_eq!(default_history_dir(&file_path), expected);
+    }
+
+    #[test]
+    fn test_refuse_to_undo_changed_files() {
+        let temp_dir = temp_dir();
+        let history_dir = temp_dir.path().join("history");
+        let file_path = write_file(temp_dir.path(), "foo.txt", "old");
+
+        let mut journal = Journal::create(&history_dir).unwrap();
+        journal.record(&file_path, b"new").unwrap();
+        fs::write(&file_path, "something else").unwrap();
+
+        let err = undo(&history_dir, Some(journal.run_id())).unwrap_err();
+        assert!(err.to_string().contains("Refusing to undo"));
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "something else");
+    }
+}
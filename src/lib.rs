@@ -1,17 +1,36 @@
 mod app;
+mod atomic;
+mod case;
 mod console;
 mod directory_patcher;
+mod encoding;
 mod file_patcher;
+mod git;
+mod journal;
+mod line_filter;
+mod plan;
 mod query;
 mod replacer;
+mod rules;
 mod settings;
 mod stats;
+mod structural;
+mod syntax;
+mod template;
+#[cfg(test)]
+mod test_utils;
 
 pub use app::run;
-pub use console::{Console, Verbosity};
+pub use case::{CaseOptions, CaseVariant};
+pub use console::{Console, OutputFormat, Verbosity};
 pub use directory_patcher::DirectoryPatcher;
 pub use file_patcher::FilePatcher;
+pub use line_filter::LineFilter;
 pub use query::Query;
-pub use replacer::{replace, Replacement};
+pub use replacer::{Replacement, replace};
+pub use rules::Rule;
 pub use settings::Settings;
-pub use stats::Stats;
+pub use stats::{SkipReason, Stats};
+pub use structural::Rewrite;
+pub use syntax::{NodeKind, SyntaxFilter};
+pub use template::Template;
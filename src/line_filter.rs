@@ -0,0 +1,118 @@
+use regex::Regex;
+use std::ops::Range;
+
+/// Restrict the matches to some lines, as used by the `--only-lines`,
+/// `--skip-lines` and `--skip-region` command line options
+#[derive(Debug, Clone, Default)]
+pub struct LineFilter {
+    /// If set, only replace on the lines matching this regex
+    pub only: Option<Regex>,
+    /// If set, don't replace on the lines matching this regex
+    pub skip: Option<Regex>,
+    /// Don't replace inside the regions starting with a line matching the
+    /// first regex, and ending with a line matching the second one.
+    /// Both marker lines are part of the region
+    pub skip_regions: Vec<(Regex, Regex)>,
+}
+
+impl LineFilter {
+    /// Whether the filter allows every line
+    pub fn is_empty(&self) -> bool {
+        self.only.is_none() && self.skip.is_none() && self.skip_regions.is_empty()
+    }
+
+    /// For each line of `contents`, tell whether replacements are allowed
+    /// there, or return None if they are allowed everywhere
+    pub(crate) fn allowed_lines(&self, contents: &str) -> Option<Vec<bool>> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut allowed: Vec<_> = contents
+            .split_inclusive('\n')
+            .map(|line| self.allows(line))
+            .collect();
+        for region in self.skipped_regions(contents) {
+            allowed[region].fill(false);
+        }
+        Some(allowed)
+    }
+
+    /// Return the ranges of line indexes covered by the skipped regions.
+    /// A region with no end marker lasts until the end of the contents
+    pub(crate) fn skipped_regions(&self, contents: &str) -> Vec<Range<usize>> {
+        let mut regions = vec![];
+        if self.skip_regions.is_empty() {
+            return regions;
+        }
+        let lines: Vec<_> = contents.split_inclusive('\n').map(trim_line).collect();
+        let mut index = 0;
+        while index < lines.len() {
+            let line = lines[index];
+            let Some((_, end)) = self
+                .skip_regions
+                .iter()
+                .find(|(start, _)| start.is_match(line))
+            else {
+                index += 1;
+                continue;
+            };
+            // Note: the end marker is looked for after the start marker, so
+            // that the same regex can be used for both
+            let stop = lines[index + 1..]
+                .iter()
+                .position(|line| end.is_match(line))
+                .map_or(lines.len(), |offset| index + offset + 2);
+            regions.push(index..stop);
+            index = stop;
+        }
+        regions
+    }
+
+    /// Whether replacements are allowed on the given line
+    pub(crate) fn allows(&self, line: &str) -> bool {
+        let line = trim_line(line);
+        self.only.as_ref().is_none_or(|only| only.is_match(line))
+            && !self.skip.as_ref().is_some_and(|skip| skip.is_match(line))
+    }
+}
+
+fn trim_line(line: &str) -> &str {
+    line.trim_end_matches(['\r', '\n'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_lines() {
+        let filter = LineFilter {
+            only: Some(Regex::new("^import").unwrap()),
+            skip: Some(Regex::new("DO NOT EDIT$").unwrap()),
+            skip_regions: vec![],
+        };
+        let contents = "import foo\nfoo()\nimport foo # DO NOT EDIT\r\n";
+        assert_eq!(
+            filter.allowed_lines(contents).unwrap(),
+            [true, false, false]
+        );
+        assert!(LineFilter::default().allowed_lines(contents).is_none());
+    }
+
+    #[test]
+    fn test_skipped_regions() {
+        let filter = LineFilter {
+            skip_regions: vec![(
+                Regex::new("BEGIN GENERATED").unwrap(),
+                Regex::new("END GENERATED").unwrap(),
+            )],
+            ..Default::default()
+        };
+        let contents = "a\n// BEGIN GENERATED\nb\n// END GENERATED\nc\n// BEGIN GENERATED\nd\n";
+        assert_eq!(filter.skipped_regions(contents), [1..4, 5..7]);
+        assert_eq!(
+            filter.allowed_lines(contents).unwrap(),
+            [true, false, false, false, true, false, false]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::encoding::{self, Decoded};
+use crate::journal::checksum;
+use crate::replacer::{Fragment, Fragments, get_output};
+
+const VERSION: u32 = 1;
+
+/// The replacements found during a dry run, saved with `--save-plan` so
+/// that exactly the same replacements can be applied later with
+/// `ruplacer apply`, without matching the files again.
+///
+/// For each file, the plan contains the checksum of its contents, and
+/// the replacements made by each query that matched, grouped by lines.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Plan {
+    version: u32,
+    files: Vec<FilePlan>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FilePlan {
+    pub(crate) path: PathBuf,
+    pub(crate) checksum: String,
+    pub(crate) encoding: String,
+    // One step for each query that matched, in order: each step is
+    // applied to the output of the previous one
+    pub(crate) steps: Vec<Vec<PlanHunk>>,
+}
+
+/// The replacements made in a group of lines, starting at `line`.
+/// The indexes of the fragments are relative to the start of that line
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PlanHunk {
+    pub(crate) line: usize,
+    pub(crate) fragments: Vec<PlanFragment>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PlanFragment {
+    pub(crate) input_index: usize,
+    pub(crate) input: String,
+    pub(crate) output_index: usize,
+    pub(crate) output: String,
+}
+
+impl Plan {
+    pub(crate) fn new() -> Self {
+        Self {
+            version: VERSION,
+            files: vec![],
+        }
+    }
+
+    pub(crate) fn push(&mut self, file_plan: FilePlan) {
+        self.files.push(file_plan);
+    }
+
+    pub(crate) fn files(&self) -> &[FilePlan] {
+        &self.files
+    }
+
+    /// Save the plan, with the files sorted by path
+    pub(crate) fn save(&mut self, path: &Path) -> Result<()> {
+        // Note: in parallel mode, the files are added in any order
+        self.files.sort_by(|a, b| a.path.cmp(&b.path));
+        let contents = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, contents)
+            .with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let plan: Plan = serde_json::from_str(&contents)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        if plan.version != VERSION {
+            bail!(
+                "Unsupported plan version in {}: {} (expected {})",
+                path.display(),
+                plan.version,
+                VERSION
+            );
+        }
+        Ok(plan)
+    }
+}
+
+impl FilePlan {
+    /// Compute the new contents of the file, encoded like the original.
+    /// Fail if the file changed since the plan was saved
+    pub(crate) fn new_contents(&self) -> Result<Vec<u8>> {
+        let bytes = std::fs::read(&self.path)
+            .with_context(|| format!("Could not read {}", self.path.display()))?;
+        if checksum(&bytes) != self.checksum {
+            bail!("{} changed since the plan was saved", self.path.display());
+        }
+        let encoding = encoding::parse_encoding(&self.encoding)?;
+        let (mut contents, text_encoding) = match encoding::decode(&bytes, Some(encoding)) {
+            Decoded::Text(contents, text_encoding) => (contents, text_encoding),
+            _ => bail!(
+                "Could not decode {} as {}",
+                self.path.display(),
+                self.encoding
+            ),
+        };
+        for hunks in &self.steps {
+            let fragments = self
+                .fragments(&contents, hunks)
+                .with_context(|| format!("Invalid plan for {}", self.path.display()))?;
+            contents = get_output(&contents, &fragments);
+        }
+        text_encoding.encode(&contents)
+    }
+
+    // Convert the hunks to fragments, with indexes relative to the
+    // start of `contents`, checking that the input text matches, and
+    // that the fragments are sorted and don't overlap
+    fn fragments(&self, contents: &str, hunks: &[PlanHunk]) -> Result<Fragments> {
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(contents.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let mut pairs = vec![];
+        let mut previous_end = 0;
+        for hunk in hunks {
+            let Some(line_start) = hunk.line.checked_sub(1).and_then(|i| line_starts.get(i)) else {
+                bail!("no line {}", hunk.line);
+            };
+            for fragment in &hunk.fragments {
+                let index = line_start + fragment.input_index;
+                if index < previous_end {
+                    bail!(
+                        "replacement of '{}' on line {} is out of order or overlaps the previous one",
+                        fragment.input,
+                        hunk.line
+                    );
+                }
+                previous_end = index + fragment.input.len();
+                if contents.get(index..index + fragment.input.len()) != Some(&fragment.input) {
+                    bail!("'{}' not found on line {}", fragment.input, hunk.line);
+                }
+                let input = Fragment {
+                    index,
+                    text: fragment.input.clone(),
+                };
+                let output = Fragment {
+                    index,
+                    text: fragment.output.clone(),
+                };
+                pairs.push((input, output));
+            }
+        }
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{temp_dir, write_file};
+    use crate::{Console, FilePatcher, Query};
+    use std::fs;
+
+    #[test]
+    fn test_save_and_apply_plan() {
+        let temp_dir = temp_dir();
+        let path = write_file(
+            temp_dir.path(),
+            "foo.txt",
+            "first line\nold is old\nlast old line\n",
+        );
+        let console = Console::new();
+        let query = Query::simple("old", "new");
+        let file_patcher = FilePatcher::new(&console, &path, &query).unwrap().unwrap();
+        let mut plan = Plan::new();
+        plan.push(file_patcher.plan().unwrap());
+        let plan_path = temp_dir.path().join("plan.json");
+        plan.save(&plan_path).unwrap();
+
+        let plan = Plan::load(&plan_path).unwrap();
+        let new_contents = plan.files()[0].new_contents().unwrap();
+        assert_eq!(new_contents, b"first line\nnew is new\nlast new line\n");
+
+        fs::write(&path, "old has changed\n").unwrap();
+        let err = plan.files()[0].new_contents().unwrap_err();
+        assert!(err.to_string().contains("changed since the plan was saved"));
+    }
+
+    #[test]
+    fn test_reject_overlapping_fragments() {
+        let temp_dir = temp_dir();
+        let path = write_file(temp_dir.path(), "foo.txt", "old is old\n");
+        let console = Console::new();
+        let query = Query::simple("old", "new");
+        let file_patcher = FilePatcher::new(&console, &path, &query).unwrap().unwrap();
+        let mut file_plan = file_patcher.plan().unwrap();
+        file_plan.steps[0][0].fragments.reverse();
+
+        let err = file_plan.new_contents().unwrap_err();
+        assert!(format!("{err:#}").contains("out of order or overlaps"));
+    }
+}
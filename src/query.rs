@@ -1,14 +1,36 @@
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+
+use crate::case::CaseOptions;
+use crate::structural::Rewrite;
+use crate::template::Template;
+
 /// A replacement Query
+#[derive(Clone)]
+#[non_exhaustive]
 pub enum Query {
     /// Substitute `old` with `new`
     Simple(String, String),
-    /// Replace the parts matching the regex with `replacement`
-    Regex(regex::Regex, String),
+    /// Replace the parts matching the regex with the rendered template
+    Regex(regex::Regex, Template),
+    /// Same as Regex, but using the fancy-regex engine, which supports
+    /// lookaround and backreferences
+    #[cfg(feature = "fancy")]
+    FancyRegex(fancy_regex::Regex, Template),
     /// Replace all instances of `pattern` with `replacement`, by
     /// using case conversion methods.
     /// This allows replacing FooBar with SpamEggs and foo_bar with spam_eggs
     /// using only one query
-    PreserveCase(String, String),
+    PreserveCase(String, String, CaseOptions),
+    /// Replace the parts matching the regex with `replacement`, converted to
+    /// the case variant of the matched text.
+    /// See `build_preserve_case_regex` to compile a regex matching all the case
+    /// variants of a pattern
+    PreserveCaseRegex(regex::Regex, Template, CaseOptions),
+    /// Replace the parts matching a structural pattern with holes, like
+    /// `foo(:[args])`, with a replacement which can use the holes too.
+    /// See the README for the syntax
+    Structural(Rewrite),
 }
 
 impl Query {
@@ -17,13 +39,374 @@ impl Query {
         Self::Simple(old.to_string(), new.to_string())
     }
 
-    /// Constructor for the Regex variant
-    pub fn regex(re: regex::Regex, replacement: &str) -> Self {
-        Self::Regex(re, replacement.to_string())
+    /// Same as `simple`, but ignore case when looking for `old`.
+    /// This builds a Regex query matching `old` literally
+    pub fn simple_ignore_case(old: &str, new: &str) -> Self {
+        let regex = regex::RegexBuilder::new(&regex::escape(old))
+            .case_insensitive(true)
+            .build()
+            .expect("escaped pattern should be a valid regex");
+        Self::Regex(regex, Template::literal(new))
+    }
+
+    /// Constructor for the Regex variant.
+    /// See the README for the syntax of the replacement
+    pub fn regex(re: regex::Regex, replacement: &str) -> Result<Self> {
+        Ok(Self::Regex(re, parse_template(replacement, false)?))
     }
 
-    /// Constructor for the PreserveCase variant
+    /// Constructor for the FancyRegex variant.
+    /// See the README for the syntax of the replacement
+    #[cfg(feature = "fancy")]
+    pub fn fancy_regex(re: fancy_regex::Regex, replacement: &str) -> Result<Self> {
+        Ok(Self::FancyRegex(re, parse_template(replacement, false)?))
+    }
+
+    /// Constructor for the PreserveCase variant, using the default case variants
     pub fn preserve_case(pattern: &str, replacement: &str) -> Self {
-        Self::PreserveCase(pattern.to_string(), replacement.to_string())
+        Self::preserve_case_with(pattern, replacement, CaseOptions::default())
+    }
+
+    /// Same as `preserve_case`, but with the given options
+    pub fn preserve_case_with(pattern: &str, replacement: &str, options: CaseOptions) -> Self {
+        Self::PreserveCase(pattern.to_string(), replacement.to_string(), options)
+    }
+
+    /// Constructor for the Structural variant
+    pub fn structural(pattern: &str, replacement: &str) -> Result<Self> {
+        let rewrite = Rewrite::new(pattern, replacement).context("Invalid structural pattern")?;
+        Ok(Self::Structural(rewrite))
+    }
+
+    /// Constructor for the PreserveCaseRegex variant
+    pub fn preserve_case_regex(
+        re: regex::Regex,
+        replacement: &str,
+        options: CaseOptions,
+    ) -> Result<Self> {
+        Ok(Self::PreserveCaseRegex(
+            re,
+            parse_template(replacement, false)?,
+            options,
+        ))
+    }
+}
+
+fn parse_template(replacement: &str, case_escapes: bool) -> Result<Template> {
+    let template = if case_escapes {
+        Template::parse_with_case_escapes(replacement)
+    } else {
+        Template::parse(replacement)
+    };
+    template.context("Invalid replacement")
+}
+
+/// The engine used to compile and run regexes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Engine {
+    /// The regex crate: fast, but without lookaround nor backreferences
+    #[default]
+    Default,
+    /// The fancy-regex crate, only available with the `fancy` feature
+    Fancy,
+}
+
+impl std::str::FromStr for Engine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Engine> {
+        match s {
+            "default" => Ok(Engine::Default),
+            "fancy" if cfg!(feature = "fancy") => Ok(Engine::Fancy),
+            "fancy" => Err(anyhow!("ruplacer was built without the 'fancy' feature")),
+            _ => Err(anyhow!("Choose between 'default' or 'fancy'")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+/// Options used when compiling the pattern and the replacement of a Regex query
+pub(crate) struct RegexOptions {
+    /// Only match whole words
+    pub(crate) word: bool,
+    /// Let '^' and '$' match at line boundaries, and '.' match new lines
+    pub(crate) multiline: bool,
+    /// Match regardless of case
+    pub(crate) ignore_case: bool,
+    /// The regex engine to use
+    pub(crate) engine: Engine,
+    /// Interpret the case conversion escapes, like `\U`, in the replacement
+    pub(crate) case_escapes: bool,
+}
+
+/// Compile `pattern` with the engine selected in the options, and
+/// return the matching query
+pub(crate) fn regex_query(
+    pattern: &str,
+    replacement: &str,
+    options: RegexOptions,
+) -> Result<Query> {
+    let template = parse_template(replacement, options.case_escapes)?;
+    match options.engine {
+        Engine::Default => {
+            let regex = build_regex(pattern, options).context("Invalid regex")?;
+            Ok(Query::Regex(regex, template))
+        }
+        #[cfg(feature = "fancy")]
+        Engine::Fancy => {
+            let regex = build_fancy_regex(pattern, options).context("Invalid regex")?;
+            Ok(Query::FancyRegex(regex, template))
+        }
+        #[cfg(not(feature = "fancy"))]
+        Engine::Fancy => Err(anyhow!(
+            "The fancy engine is not available: ruplacer was built without the 'fancy' feature"
+        )),
+    }
+}
+
+/// Compile the pattern of a Regex query
+pub(crate) fn build_regex(
+    pattern: &str,
+    options: RegexOptions,
+) -> Result<regex::Regex, regex::Error> {
+    let actual_pattern = if options.word {
+        format!(r"\b({})\b", pattern)
+    } else {
+        pattern.to_string()
+    };
+    regex::RegexBuilder::new(&actual_pattern)
+        .multi_line(options.multiline)
+        .dot_matches_new_line(options.multiline)
+        .case_insensitive(options.ignore_case)
+        .build()
+}
+
+/// Compile the pattern of a FancyRegex query
+#[cfg(feature = "fancy")]
+pub(crate) fn build_fancy_regex(
+    pattern: &str,
+    options: RegexOptions,
+) -> Result<fancy_regex::Regex> {
+    // Note: fancy-regex has no builder option for multi-line mode,
+    // so use inline flags instead
+    let flags = if options.multiline { "(?ms)" } else { "" };
+    let actual_pattern = if options.word {
+        // Note: use a non-capturing group so that backreferences
+        // in the pattern still refer to the right groups
+        format!(r"{flags}\b(?:{pattern})\b")
+    } else {
+        format!("{flags}{pattern}")
+    };
+    fancy_regex::RegexBuilder::new(&actual_pattern)
+        .case_insensitive(options.ignore_case)
+        .build()
+        .map_err(|e| anyhow!(e))
+}
+
+/// Same as `regex_query`, for a PreserveCaseRegex query
+pub(crate) fn preserve_case_regex_query(
+    pattern: &str,
+    replacement: &str,
+    options: RegexOptions,
+    case_options: CaseOptions,
+) -> Result<Query> {
+    let template = parse_template(replacement, options.case_escapes)?;
+    let regex =
+        build_preserve_case_regex(pattern, options, &case_options).context("Invalid regex")?;
+    Ok(Query::PreserveCaseRegex(regex, template, case_options))
+}
+
+/// Prefix of the names of the groups capturing the separators in the regex
+/// of a PreserveCaseRegex query
+pub(crate) const SEPARATOR_GROUP: &str = "ruplacer_separator";
+
+/// Compile the pattern of a PreserveCaseRegex query, written in snake case,
+/// so that it matches all the selected case variants: the pattern is matched
+/// regardless of case, and each `_` also matches the separators of the other
+/// variants, or nothing.
+/// Each `_` is captured in a group named after SEPARATOR_GROUP, so that the
+/// matches where words are glued together, like "foobar", can be rejected
+pub(crate) fn build_preserve_case_regex(
+    pattern: &str,
+    options: RegexOptions,
+    case_options: &CaseOptions,
+) -> Result<regex::Regex, regex::Error> {
+    let separators = case_options.separators_regex();
+    let mut num_separators = 0;
+    let mut actual_pattern = String::new();
+    let mut chars = pattern.chars().peekable();
+    let mut in_class = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                actual_pattern.push(c);
+                actual_pattern.extend(chars.next());
+            }
+            '[' => {
+                in_class = true;
+                actual_pattern.push(c);
+            }
+            ']' => {
+                in_class = false;
+                actual_pattern.push(c);
+            }
+            // Keep group names as is
+            '(' if !in_class && chars.peek() == Some(&'?') => {
+                actual_pattern.push(c);
+                actual_pattern.extend(chars.next());
+                if chars.peek() == Some(&'P') {
+                    actual_pattern.extend(chars.next());
+                }
+                if chars.peek() == Some(&'<') {
+                    for c in chars.by_ref() {
+                        actual_pattern.push(c);
+                        if c == '>' {
+                            break;
+                        }
+                    }
+                }
+            }
+            '_' if !in_class => {
+                actual_pattern.push_str(&format!(
+                    "(?P<{SEPARATOR_GROUP}{num_separators}>{separators})"
+                ));
+                num_separators += 1;
+            }
+            c => actual_pattern.push(c),
+        }
+    }
+    let options = RegexOptions {
+        ignore_case: true,
+        ..options
+    };
+    build_regex(&actual_pattern, options)
+}
+
+/// Whether the pattern contains an upper case letter, as used by `--smart-case`.
+/// For regexes, escape sequences like `\W`, classes like `\p{Lu}` and group names
+/// are not taken into account
+pub(crate) fn has_uppercase(pattern: &str, is_regex: bool) -> bool {
+    if !is_regex {
+        return pattern.chars().any(char::is_uppercase);
+    }
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match (chars.next(), chars.peek()) {
+                (Some('p' | 'P'), Some('{')) => {
+                    chars.find(|&c| c == '}');
+                }
+                // One-letter classes, like \pL
+                (Some('p' | 'P'), _) => {
+                    chars.next();
+                }
+                _ => (),
+            },
+            '(' if chars.peek() == Some(&'?') => {
+                chars.next();
+                if chars.peek() == Some(&'P') {
+                    chars.next();
+                }
+                if chars.peek() == Some(&'<') {
+                    chars.find(|&c| c == '>');
+                }
+            }
+            c if c.is_uppercase() => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replace;
+
+    #[test]
+    fn test_simple_ignore_case() {
+        let query = Query::simple_ignore_case("old.", "new$1");
+        let replacement = replace("Old. OLD. oldy", &query).unwrap().unwrap();
+        assert_eq!(replacement.output(), "new$1 new$1 oldy");
+    }
+
+    #[test]
+    fn test_regex_ignore_case() {
+        let options = RegexOptions {
+            ignore_case: true,
+            ..Default::default()
+        };
+        let regex = build_regex("f(o+)", options).unwrap();
+        let query = Query::regex(regex, "b$1").unwrap();
+        let replacement = replace("FOO foo", &query).unwrap().unwrap();
+        assert_eq!(replacement.output(), "bOO boo");
+    }
+
+    #[cfg(feature = "fancy")]
+    #[test]
+    fn test_fancy_regex() {
+        let regex = build_fancy_regex("foo(?!Bar)", RegexOptions::default()).unwrap();
+        let query = Query::fancy_regex(regex, "spam").unwrap();
+        let replacement = replace("foo fooBar fooBaz", &query).unwrap().unwrap();
+        assert_eq!(replacement.output(), "spam fooBar spamBaz");
+
+        let regex = build_fancy_regex(r"(\w+) \1", RegexOptions::default()).unwrap();
+        let query = Query::fancy_regex(regex, "$1").unwrap();
+        let replacement = replace("the the end", &query).unwrap().unwrap();
+        assert_eq!(replacement.output(), "the end");
+    }
+
+    #[cfg(feature = "fancy")]
+    #[test]
+    fn test_fancy_regex_errors_are_reported() {
+        let regex = fancy_regex::RegexBuilder::new(r"(?=a)(a|aa)*c")
+            .backtrack_limit(100)
+            .build()
+            .unwrap();
+        let query = Query::fancy_regex(regex, "b").unwrap();
+        assert!(replace(&"a".repeat(30), &query).is_err());
+    }
+
+    #[test]
+    fn test_preserve_case_regex() {
+        let case_options = CaseOptions::default();
+        let regex =
+            build_preserve_case_regex(r"foo_(bar|baz)", RegexOptions::default(), &case_options)
+                .unwrap();
+        let query = Query::preserve_case_regex(regex, "spam_$1", case_options).unwrap();
+        let replacement = replace("foo_bar FooBaz FOO_BAR foo-baz fooBar", &query)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            replacement.output(),
+            "spam_bar SpamBaz SPAM_BAR spam-baz spamBar"
+        );
+        // Only the matches written in one of the case variants are replaced
+        let replacement = replace("foobarista FoO_bar FOOBAR foo_bar", &query)
+            .unwrap()
+            .unwrap();
+        assert_eq!(replacement.output(), "foobarista FoO_bar FOOBAR spam_bar");
+
+        let regex = build_preserve_case_regex(
+            r"(?P<first_name>[a_z]+)_x",
+            RegexOptions::default(),
+            &CaseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            regex.as_str(),
+            r"(?P<first_name>[a_z]+)(?P<ruplacer_separator0>(?:\-|_)?)x"
+        );
+    }
+
+    #[test]
+    fn test_has_uppercase() {
+        assert!(has_uppercase("Foo", false));
+        assert!(!has_uppercase("foo", false));
+        assert!(has_uppercase(r"\w+Foo", true));
+        assert!(!has_uppercase(r"\W\S\p{Lu}\PL", true));
+        assert!(!has_uppercase(r"(?P<Name>foo)(?<Other>bar)(?i)", true));
+        assert!(has_uppercase(r"(?:Foo)", true));
     }
 }
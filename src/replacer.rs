@@ -1,15 +1,17 @@
-use crate::query::Query;
-use inflector::cases::camelcase::to_camel_case;
-use inflector::cases::kebabcase::to_kebab_case;
-use inflector::cases::pascalcase::to_pascal_case;
-use inflector::cases::screamingsnakecase::to_screaming_snake_case;
-use inflector::cases::snakecase::to_snake_case;
-use inflector::cases::traincase::to_train_case;
+use std::ops::Range;
 
+use crate::case::CaseOptions;
+use crate::query::{Query, SEPARATOR_GROUP};
+use crate::structural::Rewrite;
+use crate::template::{Captured, Template};
+
+use anyhow::Result;
 use regex::Regex;
 
 /// Execute a query on a line of input.
-/// If there was a match, return a Replacement
+/// If there was a match, return a Replacement.
+/// Fail if the regex engine could not run the query, for instance when
+/// the backtracking limit of the fancy engine is exceeded
 ///
 /// Example
 ///
@@ -18,26 +20,21 @@ use regex::Regex;
 ///
 /// let input = "this is some old text";
 /// let query = Query::simple("old", "new");
-/// let replacement = replace(input, &query).unwrap();
+/// let replacement = replace(input, &query).unwrap().unwrap();
 /// let output = replacement.output();
 /// assert_eq!(output, "this is some new text");
 /// ```
-pub fn replace<'a>(input: &'a str, query: &Query) -> Option<Replacement<'a>> {
+pub fn replace<'a>(input: &'a str, query: &Query) -> Result<Option<Replacement<'a>>> {
     // This occurs in two steps:
     // 1/ Compute the input and output fragments - this depends
     //    on the query enum variant
     // 2/ Use the list of fragments to build the output string
     //   (this uses the same code for every query enum variant)
-    let fragments = get_fragments(input, query);
+    let fragments = get_fragments(input, query)?;
     if fragments.is_empty() {
-        return None;
+        return Ok(None);
     }
-    let output = get_output(input, &fragments);
-    Some(Replacement {
-        fragments,
-        input,
-        output,
-    })
+    Ok(Some(Replacement::new(input, fragments)))
 }
 
 #[derive(Debug)]
@@ -48,7 +45,16 @@ pub struct Replacement<'a> {
     output: String,
 }
 
-impl Replacement<'_> {
+impl<'a> Replacement<'a> {
+    pub(crate) fn new(input: &'a str, fragments: Fragments) -> Self {
+        let output = get_output(input, &fragments);
+        Self {
+            fragments,
+            input,
+            output,
+        }
+    }
+
     /// Return the output string
     pub fn output(&self) -> &str {
         &self.output
@@ -59,32 +65,153 @@ impl Replacement<'_> {
         self.input
     }
 
-    pub(crate) fn num_fragments(&self) -> usize {
-        self.fragments.len()
-    }
-
     pub(crate) fn fragments(&self) -> &Fragments {
         &self.fragments
     }
 }
 
 // A list of input_fragment, output_fragment
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub(crate) struct Fragments(Vec<(Fragment, Fragment)>);
 
+impl From<(Fragment, Fragment)> for Fragments {
+    fn from(pair: (Fragment, Fragment)) -> Self {
+        Self(vec![pair])
+    }
+}
+
+// Note: the output indexes are recomputed from the input indexes,
+// so the fragments must be sorted and must not overlap
+impl FromIterator<(Fragment, Fragment)> for Fragments {
+    fn from_iter<I: IntoIterator<Item = (Fragment, Fragment)>>(iter: I) -> Self {
+        let mut fragments = Self(iter.into_iter().collect());
+        fragments.reindex();
+        fragments
+    }
+}
+
 impl Fragments {
     fn new() -> Self {
         Self(vec![])
     }
 
-    fn len(&self) -> usize {
+    pub(crate) fn len(&self) -> usize {
         self.0.len()
     }
 
-    fn is_empty(&self) -> bool {
+    pub(crate) fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
 
+    /// Only keep the fragments for which `choose` returns some text,
+    /// and use that text as the new output fragment
+    pub(crate) fn filter_map<F>(self, mut choose: F) -> Fragments
+    where
+        F: FnMut(&Fragment, &Fragment) -> Option<String>,
+    {
+        let mut fragments = Fragments::new();
+        for (input_fragment, output_fragment) in self.0 {
+            if let Some(text) = choose(&input_fragment, &output_fragment) {
+                let output_fragment = Fragment {
+                    index: output_fragment.index,
+                    text,
+                };
+                fragments.0.push((input_fragment, output_fragment));
+            }
+        }
+        fragments.reindex();
+        fragments
+    }
+
+    // Recompute the output indexes from the input indexes, assuming
+    // the fragments are sorted and do not overlap
+    fn reindex(&mut self) {
+        let mut delta: isize = 0;
+        for (input_fragment, output_fragment) in &mut self.0 {
+            output_fragment.index = input_fragment.index.saturating_add_signed(delta);
+            delta += output_fragment.text.len() as isize - input_fragment.text.len() as isize;
+        }
+    }
+
+    /// Group the fragments by the lines of `input` they span.
+    /// Return the index of the first line of each group (starting at 0), its
+    /// byte range, and the fragments it contains, relative to the start of the range.
+    /// Note: this runs in a single pass over `input`, since the fragments are sorted
+    pub(crate) fn group_by_lines(self, input: &str) -> Vec<(usize, Range<usize>, Fragments)> {
+        let line_end = |index: usize| {
+            input[index..]
+                .find('\n')
+                .map_or(input.len(), |i| index + i + 1)
+        };
+
+        let mut groups: Vec<(usize, Range<usize>, Fragments)> = vec![];
+        // Number of line delimiters before `counted`
+        let mut line = 0;
+        let mut counted = 0;
+        for (input_fragment, output_fragment) in self.0 {
+            // Note: the last byte of the fragment may be a line delimiter,
+            // which belongs to the line it terminates. Replacing it joins
+            // that line with the next one, so both go in the same group
+            let start = input_fragment.index;
+            let end = start + input_fragment.text.len();
+            let end = match input_fragment.text.ends_with('\n') {
+                true => line_end(end),
+                false => line_end(end.saturating_sub(1).max(start)),
+            };
+            if let Some((_, current, fragments)) = groups.last_mut()
+                && start < current.end
+            {
+                current.end = current.end.max(end);
+                fragments.0.push((input_fragment, output_fragment));
+                continue;
+            }
+            // The previous group ends at the start of a line, so there is
+            // no need to look for the start of this one before it
+            let previous_end = groups.last().map_or(0, |(_, range, _)| range.end);
+            let range_start = input[previous_end..start]
+                .rfind('\n')
+                .map_or(previous_end, |i| previous_end + i + 1);
+            line += input[counted..range_start].matches('\n').count();
+            counted = range_start;
+            let range = range_start..end;
+            groups.push((
+                line,
+                range,
+                Fragments(vec![(input_fragment, output_fragment)]),
+            ));
+        }
+
+        for (_, range, fragments) in &mut groups {
+            for (input_fragment, _) in &mut fragments.0 {
+                input_fragment.index -= range.start;
+            }
+            fragments.reindex();
+        }
+        groups
+    }
+
+    /// Only keep the fragments spanning lines of `input` which are all allowed
+    pub(crate) fn retain_lines(self, input: &str, allowed_lines: &[bool]) -> Fragments {
+        // Note: the fragments are sorted, so line numbers can be computed
+        // by counting the line delimiters since the previous fragment
+        let mut line = 0;
+        let mut counted = 0;
+        let mut line_number = |index: usize| {
+            line += input[counted..index].matches('\n').count();
+            counted = index;
+            line
+        };
+        self.filter_map(|input_fragment, output_fragment| {
+            let first = line_number(input_fragment.index);
+            let last =
+                line_number(input_fragment.index + input_fragment.text.len().saturating_sub(1));
+            allowed_lines[first..=last]
+                .iter()
+                .all(|&allowed| allowed)
+                .then(|| output_fragment.text.clone())
+        })
+    }
+
     fn add(
         &mut self,
         (input_index, input_text): (usize, &str),
@@ -115,15 +242,18 @@ impl<'a> IntoIterator for &'a Fragments {
 
 /// Represent a fragment of text, similar to the data structure returned
 /// by String::match_indices
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Fragment {
     pub(crate) index: usize,
     pub(crate) text: String,
 }
 
 trait Replacer {
-    // return index of the match, input_text, output_text, or None
-    fn replace(&self, buff: &str) -> Option<(usize, String, String)>;
+    // Look for the first match starting at or after `start`, and return
+    // its index in `input`, input_text, output_text, or None.
+    // Note: the whole input is given, so that anchors like ^ or \b only
+    // match where they would in the input
+    fn replace(&self, input: &str, start: usize) -> Result<Option<(usize, String, String)>>;
 }
 
 struct SubstringReplacer<'a> {
@@ -141,13 +271,15 @@ impl<'a> SubstringReplacer<'a> {
 }
 
 impl Replacer for SubstringReplacer<'_> {
-    fn replace(&self, buff: &str) -> Option<(usize, String, String)> {
-        let index = buff.find(self.pattern)?;
-        Some((
-            index,
+    fn replace(&self, input: &str, start: usize) -> Result<Option<(usize, String, String)>> {
+        let Some(offset) = input[start..].find(self.pattern) else {
+            return Ok(None);
+        };
+        Ok(Some((
+            start + offset,
             self.pattern.to_string(),
             self.replacement.to_string(),
-        ))
+        )))
     }
 }
 
@@ -162,7 +294,8 @@ impl<'a> PreserveCaseReplacer<'a> {
 }
 
 impl Replacer for PreserveCaseReplacer<'_> {
-    fn replace(&self, buff: &str) -> Option<(usize, String, String)> {
+    fn replace(&self, input: &str, start: usize) -> Result<Option<(usize, String, String)>> {
+        let buff = &input[start..];
         // Note: replacing using preserve_case can get tricky
         //
         // Let's say self.items contains
@@ -192,98 +325,274 @@ impl Replacer for PreserveCaseReplacer<'_> {
             }
         }
 
-        let best_item = &self.items[best_pattern?];
-        let (pattern, replacement) = best_item;
-        Some((best_index, pattern.to_string(), replacement.to_string()))
+        let Some(best_pattern) = best_pattern else {
+            return Ok(None);
+        };
+        let (pattern, replacement) = &self.items[best_pattern];
+        Ok(Some((
+            start + best_index,
+            pattern.to_string(),
+            replacement.to_string(),
+        )))
     }
 }
 
 struct RegexReplacer<'a> {
     regex: &'a Regex,
-    replacement: &'a str,
+    template: &'a Template,
 }
 
 impl<'a> RegexReplacer<'a> {
-    fn new(regex: &'a Regex, replacement: &'a str) -> Self {
-        Self { regex, replacement }
+    fn new(regex: &'a Regex, template: &'a Template) -> Self {
+        Self { regex, template }
     }
 }
 
 impl Replacer for RegexReplacer<'_> {
-    fn replace(&self, buff: &str) -> Option<(usize, String, String)> {
-        let regex_match = self.regex.find(buff)?;
+    fn replace(&self, input: &str, start: usize) -> Result<Option<(usize, String, String)>> {
+        let Some(captures) = self.regex.captures_at(input, start) else {
+            return Ok(None);
+        };
+        let regex_match = captures.get(0).expect("group 0 is always set");
         let index = regex_match.start();
         let input_text = regex_match.as_str();
-        let output_text = self.regex.replacen(input_text, 1, self.replacement);
-        Some((index, input_text.to_string(), output_text.to_string()))
+        let output_text = self.template.render(&captures);
+        Ok(Some((index, input_text.to_string(), output_text)))
+    }
+}
+
+#[cfg(feature = "fancy")]
+struct FancyRegexReplacer<'a> {
+    regex: &'a fancy_regex::Regex,
+    template: &'a Template,
+}
+
+#[cfg(feature = "fancy")]
+impl<'a> FancyRegexReplacer<'a> {
+    fn new(regex: &'a fancy_regex::Regex, template: &'a Template) -> Self {
+        Self { regex, template }
+    }
+}
+
+#[cfg(feature = "fancy")]
+impl Replacer for FancyRegexReplacer<'_> {
+    fn replace(&self, input: &str, start: usize) -> Result<Option<(usize, String, String)>> {
+        // Note: matching fails when the backtracking limit is exceeded
+        let Some(captures) = self.regex.captures_from_pos(input, start)? else {
+            return Ok(None);
+        };
+        let regex_match = captures.get(0).expect("group 0 is always set");
+        let index = regex_match.start();
+        let input_text = regex_match.as_str();
+        let output_text = self.template.render(&captures);
+        Ok(Some((index, input_text.to_string(), output_text)))
+    }
+}
+
+struct PreserveCaseRegexReplacer<'a> {
+    regex: &'a Regex,
+    template: &'a Template,
+    options: &'a CaseOptions,
+    // Indexes of the groups of the pattern, and of the groups capturing
+    // the separators - see `build_preserve_case_regex`
+    groups: Vec<usize>,
+    separators: Vec<usize>,
+}
+
+impl<'a> PreserveCaseRegexReplacer<'a> {
+    fn new(regex: &'a Regex, template: &'a Template, options: &'a CaseOptions) -> Self {
+        let (separators, groups) = regex
+            .capture_names()
+            .enumerate()
+            .partition(|(_, name)| name.is_some_and(|name| name.starts_with(SEPARATOR_GROUP)));
+        let indexes = |groups: Vec<(usize, _)>| groups.into_iter().map(|(i, _)| i).collect();
+        Self {
+            regex,
+            template,
+            options,
+            groups: indexes(groups),
+            separators: indexes(separators),
+        }
+    }
+}
+
+impl Replacer for PreserveCaseRegexReplacer<'_> {
+    fn replace(&self, input: &str, start: usize) -> Result<Option<(usize, String, String)>> {
+        // Note: skip the matches not written in one of the case variants
+        let mut start = start;
+        while let Some(captures) = self.regex.captures_at(input, start) {
+            let regex_match = captures.get(0).expect("group 0 is always set");
+            let glued = self
+                .separators
+                .iter()
+                .filter_map(|&index| captures.get(index))
+                .any(|separator| {
+                    separator.is_empty() && !is_case_boundary(input, separator.start())
+                });
+            let captured = PatternGroups {
+                captures: &captures,
+                groups: &self.groups,
+            };
+            let output_text = self.template.render(&captured);
+            let input_text = regex_match.as_str();
+            if let Some(output_text) = self.options.convert_like(input_text, &output_text, glued) {
+                return Ok(Some((
+                    regex_match.start(),
+                    input_text.to_string(),
+                    output_text,
+                )));
+            }
+            // Note: move past empty matches, one character at a time
+            start = match input[regex_match.end()..].chars().next() {
+                Some(c) if regex_match.is_empty() => regex_match.end() + c.len_utf8(),
+                Some(_) => regex_match.end(),
+                None => return Ok(None),
+            };
+        }
+        Ok(None)
+    }
+}
+
+// The groups of a PreserveCaseRegex query, as written in the pattern, without
+// the groups capturing the separators
+struct PatternGroups<'a, 'h> {
+    captures: &'a regex::Captures<'h>,
+    groups: &'a [usize],
+}
+
+impl Captured for PatternGroups<'_, '_> {
+    fn index(&self, index: usize) -> Option<&str> {
+        let index = *self.groups.get(index)?;
+        self.captures.get(index).map(|m| m.as_str())
+    }
+
+    fn name(&self, name: &str) -> Option<&str> {
+        self.captures.name(name).map(|m| m.as_str())
+    }
+}
+
+// Whether words change at `index` because of the case of the letters, like
+// in "fooBar" or "HTTPServer"
+fn is_case_boundary(input: &str, index: usize) -> bool {
+    let mut before = input[..index].chars().rev();
+    let mut after = input[index..].chars();
+    match (before.next(), after.next(), after.next()) {
+        (Some(previous), Some(next), _) if next.is_uppercase() && !previous.is_uppercase() => {
+            previous.is_alphanumeric()
+        }
+        (Some(previous), Some(next), Some(following)) => {
+            previous.is_uppercase() && next.is_uppercase() && following.is_lowercase()
+        }
+        _ => false,
     }
 }
 
+struct StructuralReplacer<'a> {
+    rewrite: &'a Rewrite,
+}
+
+impl<'a> StructuralReplacer<'a> {
+    fn new(rewrite: &'a Rewrite) -> Self {
+        Self { rewrite }
+    }
+}
+
+impl Replacer for StructuralReplacer<'_> {
+    fn replace(&self, input: &str, start: usize) -> Result<Option<(usize, String, String)>> {
+        let Some((index, input_text, output_text)) = self.rewrite.replace(&input[start..]) else {
+            return Ok(None);
+        };
+        Ok(Some((start + index, input_text, output_text)))
+    }
+}
+
+/// Execute a query on each line of the input, and return the fragments
+/// with indexes relative to the whole input.
+/// If set, `allowed_lines` tells on which lines to execute the query
+pub(crate) fn get_fragments_by_line(
+    input: &str,
+    query: &Query,
+    allowed_lines: Option<&[bool]>,
+) -> Result<Fragments> {
+    let mut fragments = Fragments::new();
+    let mut line_start = 0;
+    for (i, line) in input.split_inclusive('\n').enumerate() {
+        if allowed_lines.is_some_and(|allowed| !allowed[i]) {
+            line_start += line.len();
+            continue;
+        }
+        for (mut input_fragment, output_fragment) in get_fragments(line, query)?.0 {
+            input_fragment.index += line_start;
+            fragments.0.push((input_fragment, output_fragment));
+        }
+        line_start += line.len();
+    }
+    fragments.reindex();
+    Ok(fragments)
+}
+
 /// Return a list of fragments for input string and output string
 /// Both lists of fragments will be used for:
 ///    - computing the output string
 ///    - printing the patch
-fn get_fragments(input: &str, query: &Query) -> Fragments {
+pub(crate) fn get_fragments(input: &str, query: &Query) -> Result<Fragments> {
     match query {
         Query::Simple(pattern, replacement) => {
             let finder = SubstringReplacer::new(pattern, replacement);
             get_fragments_with_finder(input, finder)
         }
-        Query::Regex(regex, replacement) => {
-            let finder = RegexReplacer::new(regex, replacement);
+        Query::Regex(regex, template) => {
+            let finder = RegexReplacer::new(regex, template);
             get_fragments_with_finder(input, finder)
         }
-        Query::PreserveCase(pattern, replacement) => {
-            fn to_ada_case(input: &str) -> String {
-                to_train_case(input).replace('-', "_")
-            }
-
-            let mut items = vec![];
-            for function in &[
-                to_ada_case,
-                to_camel_case,
-                to_kebab_case,
-                to_pascal_case,
-                to_screaming_snake_case,
-                to_snake_case,
-                to_train_case,
-            ] {
-                items.push((function(pattern), function(replacement)));
-            }
-
+        #[cfg(feature = "fancy")]
+        Query::FancyRegex(regex, template) => {
+            let finder = FancyRegexReplacer::new(regex, template);
+            get_fragments_with_finder(input, finder)
+        }
+        Query::PreserveCase(pattern, replacement, options) => {
+            let items = options.items(pattern, replacement);
             let finder = PreserveCaseReplacer::new(&items);
             get_fragments_with_finder(input, finder)
         }
+        Query::PreserveCaseRegex(regex, template, options) => {
+            let finder = PreserveCaseRegexReplacer::new(regex, template, options);
+            get_fragments_with_finder(input, finder)
+        }
+        Query::Structural(rewrite) => {
+            let finder = StructuralReplacer::new(rewrite);
+            get_fragments_with_finder(input, finder)
+        }
     }
 }
 
-fn get_fragments_with_finder(input: &str, finder: impl Replacer) -> Fragments {
-    // Algorithm: call finder.find(). If it matches, bump input_index and output_text
+fn get_fragments_with_finder(input: &str, finder: impl Replacer) -> Result<Fragments> {
+    // Algorithm: call finder.replace(). If it matches, bump input_index and output_index
     // using the length of the input text and the length of the output text respectively
-    // Truncate the input string at each step to keep finding successive matches:
+    // Look for the next match after the previous one to keep finding successive matches:
     //
-    // step | buf                      | input_fragment | output_fragment
-    // -----|--------------------------|----------------|----------
-    //    0 | "my tea is the best tea" |     (3, "tea") |  (3, "coffee")
-    //    1 | "  is the best tea"      |    (19, "tea") | (22, "coffee")
-    //    2 | " !"                     |      n/a       | n/a
+    // step | start | input_fragment | output_fragment
+    // -----|-------|----------------|----------------
+    //    0 |     0 |     (3, "tea") |  (3, "coffee")
+    //    1 |     6 |    (19, "tea") | (22, "coffee")
+    //    2 |    22 |      n/a       | n/a
     //
     let mut fragments = Fragments::new();
     let mut input_index = 0;
     let mut output_index = 0;
-    while let Some(res) = finder.replace(&input[input_index..]) {
+    while let Some(res) = finder.replace(input, input_index)? {
         let (index, input_text, output_text) = res;
-        input_index += index;
-        output_index += index;
+        output_index += index - input_index;
+        input_index = index;
         fragments.add((input_index, &input_text), (output_index, &output_text));
         input_index += input_text.len();
         output_index += output_text.len();
     }
 
-    fragments
+    Ok(fragments)
 }
 
-fn get_output(input: &str, fragments: &Fragments) -> String {
+pub(crate) fn get_output(input: &str, fragments: &Fragments) -> String {
     let mut current_index = 0;
     let mut output = String::new();
     for (input_fragment, output_fragment) in fragments.into_iter() {
@@ -318,7 +627,7 @@ mod tests {
         let pattern = "thé";
         let replacement = "café";
         let query = Query::simple(pattern, replacement);
-        let replacement = replace(input, &query).unwrap();
+        let replacement = replace(input, &query).unwrap().unwrap();
         assert_eq!(
             replacement.output(),
             "Mon café c'est le meilleur des cafés !"
@@ -331,7 +640,7 @@ mod tests {
         let pattern = "old";
         let replacement = "new";
         let query = Query::simple(pattern, replacement);
-        let replacement = replace(input, &query).unwrap();
+        let replacement = replace(input, &query).unwrap().unwrap();
         assert_eq!(replacement.output(), "new new new");
     }
 
@@ -343,7 +652,7 @@ mod tests {
         let pattern = "old";
         let replacement = "new";
         let query = Query::simple(pattern, replacement);
-        let replacement = replace(input, &query).unwrap();
+        let replacement = replace(input, &query).unwrap().unwrap();
         let console = Console::new();
         console.print_replacement("foo.txt:3 ", &replacement);
     }
@@ -354,7 +663,7 @@ mod tests {
         let pattern = "foo_bar";
         let replacement = "spam_eggs";
         let query = Query::preserve_case(pattern, replacement);
-        let replacement = replace(input, &query).unwrap();
+        let replacement = replace(input, &query).unwrap().unwrap();
         assert_eq!(replacement.output(), "let spam_eggs = SpamEggs::new();");
     }
 
@@ -362,17 +671,77 @@ mod tests {
     fn test_regex_with_substitutions() {
         let input = "first, second";
         let regex = Regex::new(r"(\w+), (\w+)").unwrap();
-        let query = Query::regex(regex, r"$2 $1");
-        let replacement = replace(input, &query).unwrap();
+        let query = Query::regex(regex, r"$2 $1").unwrap();
+        let replacement = replace(input, &query).unwrap().unwrap();
         assert_eq!(replacement.output(), "second first");
     }
 
+    #[test]
+    fn test_group_fragments_by_lines() {
+        let input = "old\nnothing\nold and\nold\n";
+        let regex = Regex::new("old and\nold").unwrap();
+        let query = Query::regex(regex, "new").unwrap();
+        let mut fragments = get_fragments(input, &query).unwrap();
+        fragments.0.insert(
+            0,
+            (
+                Fragment {
+                    index: 0,
+                    text: "old".to_string(),
+                },
+                Fragment {
+                    index: 0,
+                    text: "new".to_string(),
+                },
+            ),
+        );
+        let groups = fragments.group_by_lines(input);
+        assert_eq!(groups.len(), 2);
+
+        let (line, range, fragments) = &groups[0];
+        assert_eq!(*line, 0);
+        assert_eq!(&input[range.clone()], "old\n");
+        assert_eq!(fragments.len(), 1);
+
+        let (line, range, fragments) = &groups[1];
+        assert_eq!(*line, 2);
+        assert_eq!(&input[range.clone()], "old and\nold\n");
+        let replacement = Replacement::new(&input[range.clone()], fragments.clone());
+        assert_eq!(replacement.output(), "new\n");
+    }
+
+    #[test]
+    fn test_joined_lines_are_grouped() {
+        let input = "a\nb\nc\nd\n";
+        let query = Query::regex(Regex::new("\n").unwrap(), " ").unwrap();
+        let groups = get_fragments(input, &query).unwrap().group_by_lines(input);
+        assert_eq!(groups.len(), 1);
+
+        let (line, range, fragments) = &groups[0];
+        assert_eq!(*line, 0);
+        assert_eq!(*range, 0..input.len());
+        assert_eq!(fragments.len(), 4);
+    }
+
     #[test]
     fn test_simple_regex() {
         let input = "old is old";
         let regex = Regex::new("old").unwrap();
-        let query = Query::regex(regex, "new");
-        let replacement = replace(input, &query).unwrap();
+        let query = Query::regex(regex, "new").unwrap();
+        let replacement = replace(input, &query).unwrap().unwrap();
         assert_eq!(replacement.output(), "new is new");
     }
+
+    #[test]
+    fn test_anchors_only_match_once() {
+        let input = "foofoo\nfoo bar";
+        for (pattern, expected) in [
+            ("(?m)^foo", "barfoo\nbar bar"),
+            (r"\bfoo", "barfoo\nbar bar"),
+        ] {
+            let query = Query::regex(Regex::new(pattern).unwrap(), "bar").unwrap();
+            let fragments = get_fragments(input, &query).unwrap();
+            assert_eq!(get_output(input, &fragments), expected);
+        }
+    }
 }
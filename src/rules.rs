@@ -0,0 +1,308 @@
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::case::{CaseOptions, CaseVariant};
+use crate::directory_patcher::build_file_types;
+use crate::query::{Engine, RegexOptions, preserve_case_regex_query, regex_query};
+use crate::{Query, Settings};
+
+/// A query, optionally restricted to some file types
+///
+/// Rules are usually read from a TOML file looking like this:
+/// ```toml
+/// [[rule]]
+/// pattern = "old"
+/// replacement = "new"
+///
+/// [[rule]]
+/// pattern = "foo_bar"
+/// replacement = "spam_eggs"
+/// mode = "preserve-case"
+/// types = ["rust", "*.toml"]
+/// types-not = ["markdown"]
+/// ```
+///
+/// `mode` is one of `simple`, `regex`, `word`, `preserve-case`, `preserve-case-regex`
+/// or `structural` (default: `regex`),
+/// `types` and `types-not` work like the `--type` and `--type-not` command line options,
+/// `ignore-case = true` works like `--ignore-case`, `engine = "fancy"` works
+/// like `--engine fancy` for the `regex` and `word` modes, `case-escapes = true`
+/// works like `--case-escapes` for the `regex`, `word` and `preserve-case-regex` modes, and
+/// `case-variants = ["snake", "dot"]`, `acronyms = ["grpc"]` and
+/// `inflect-plurals = true` work like `--case-variants`, `--acronyms` and
+/// `--inflect-plurals` for the `preserve-case` mode.
+#[derive(Clone)]
+pub struct Rule {
+    query: Query,
+    description: String,
+    file_types: Option<ignore::types::Types>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(rename = "rule", default)]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RuleSpec {
+    pattern: String,
+    replacement: String,
+    #[serde(default)]
+    mode: Mode,
+    #[serde(default)]
+    types: Vec<String>,
+    #[serde(default)]
+    types_not: Vec<String>,
+    #[serde(default)]
+    ignore_case: bool,
+    #[serde(default)]
+    engine: Engine,
+    #[serde(default)]
+    case_escapes: bool,
+    #[serde(default)]
+    case_variants: Vec<CaseVariant>,
+    #[serde(default)]
+    acronyms: Vec<String>,
+    #[serde(default)]
+    inflect_plurals: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Mode {
+    Simple,
+    #[default]
+    Regex,
+    Word,
+    PreserveCase,
+    PreserveCaseRegex,
+    Structural,
+}
+
+impl Rule {
+    /// Create a rule applying the query to every file
+    pub fn new(query: Query) -> Self {
+        Self {
+            query,
+            description: String::new(),
+            file_types: None,
+        }
+    }
+
+    /// Read a list of rules from a TOML file.
+    /// Regex patterns are compiled according to the settings (for instance,
+    /// `multiline`)
+    pub fn from_file(path: &Path, settings: &Settings) -> Result<Vec<Rule>> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let rules_file: RulesFile = toml::from_str(&contents)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        rules_file
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                Self::from_spec(spec, settings)
+                    .with_context(|| format!("Invalid rule #{} in {}", i + 1, path.display()))
+            })
+            .collect()
+    }
+
+    fn from_spec(spec: RuleSpec, settings: &Settings) -> Result<Rule> {
+        let RuleSpec {
+            pattern,
+            replacement,
+            mode,
+            types,
+            types_not,
+            ignore_case,
+            engine,
+            case_escapes,
+            case_variants,
+            acronyms,
+            inflect_plurals,
+        } = spec;
+        let options = RegexOptions {
+            word: matches!(mode, Mode::Word),
+            multiline: settings.multiline,
+            ignore_case,
+            engine,
+            case_escapes,
+        };
+        let preserve_case = matches!(mode, Mode::PreserveCase | Mode::PreserveCaseRegex);
+        if (!case_variants.is_empty() || !acronyms.is_empty()) && !preserve_case {
+            bail!("case-variants and acronyms can only be used with the preserve-case modes")
+        }
+        if inflect_plurals && !matches!(mode, Mode::PreserveCase) {
+            bail!("inflect-plurals can only be used with the preserve-case mode")
+        }
+        if ignore_case && (preserve_case || matches!(mode, Mode::Structural)) {
+            bail!("ignore-case can't be used with the preserve-case and structural modes")
+        }
+        if engine != Engine::Default && !matches!(mode, Mode::Regex | Mode::Word) {
+            bail!("engine can only be used with the regex and word modes")
+        }
+        if case_escapes && !matches!(mode, Mode::Regex | Mode::Word | Mode::PreserveCaseRegex) {
+            bail!(
+                "case-escapes can only be used with the regex, word and preserve-case-regex modes"
+            )
+        }
+        let case_options = if case_variants.is_empty() {
+            CaseOptions::default()
+        } else {
+            CaseOptions::with_variants(&case_variants)
+        };
+        let case_options = case_options
+            .with_acronyms(&acronyms)
+            .with_plurals(inflect_plurals);
+        let query = match mode {
+            Mode::Simple if ignore_case => Query::simple_ignore_case(&pattern, &replacement),
+            Mode::Simple => Query::simple(&pattern, &replacement),
+            Mode::Regex | Mode::Word => regex_query(&pattern, &replacement, options)?,
+            Mode::PreserveCase => Query::preserve_case_with(&pattern, &replacement, case_options),
+            Mode::PreserveCaseRegex => {
+                preserve_case_regex_query(&pattern, &replacement, options, case_options)?
+            }
+            Mode::Structural => Query::structural(&pattern, &replacement)?,
+        };
+        let file_types = if types.is_empty() && types_not.is_empty() {
+            None
+        } else {
+            Some(build_file_types(&types, &types_not)?)
+        };
+        Ok(Rule {
+            query,
+            description: format!("{pattern} -> {replacement}"),
+            file_types,
+        })
+    }
+
+    /// The query of this rule
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    /// A short description of the rule, like "old -> new"
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Whether the rule should be applied to the given file
+    pub fn applies_to(&self, path: &Path) -> bool {
+        match &self.file_types {
+            None => true,
+            Some(file_types) => !file_types.matched(path, false).is_ignore(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replace;
+
+    #[test]
+    fn test_parse_rules() {
+        let contents = r#"
+[[rule]]
+pattern = 'old'
+replacement = 'new'
+
+[[rule]]
+pattern = "foo_bar"
+replacement = "spam_eggs"
+mode = "preserve-case"
+types = ["py"]
+"#;
+        let rules_file: RulesFile = toml::from_str(contents).unwrap();
+        let rules: Vec<_> = rules_file
+            .rules
+            .into_iter()
+            .map(|spec| Rule::from_spec(spec, &Settings::default()).unwrap())
+            .collect();
+        assert_eq!(rules.len(), 2);
+
+        let first = &rules[0];
+        assert_eq!(first.description(), "old -> new");
+        assert!(first.applies_to(Path::new("foo.rs")));
+
+        let second = &rules[1];
+        assert!(second.applies_to(Path::new("foo.py")));
+        assert!(!second.applies_to(Path::new("foo.rs")));
+        let replacement = replace("FooBar", second.query()).unwrap().unwrap();
+        assert_eq!(replacement.output(), "SpamEggs");
+    }
+
+    #[test]
+    fn test_reject_unknown_mode() {
+        let contents = r#"
+[[rule]]
+pattern = "old"
+replacement = "new"
+mode = "fuzzy"
+"#;
+        let err = toml::from_str::<RulesFile>(contents).unwrap_err();
+        assert!(err.to_string().contains("unknown variant"));
+    }
+
+    #[test]
+    fn test_preserve_case_regex_mode() {
+        let contents = r#"
+[[rule]]
+pattern = 'foo_(bar|baz)'
+replacement = 'spam_$1'
+mode = "preserve-case-regex"
+"#;
+        let mut rules_file: RulesFile = toml::from_str(contents).unwrap();
+        let spec = rules_file.rules.remove(0);
+        let rule = Rule::from_spec(spec, &Settings::default()).unwrap();
+        let replacement = replace("FooBaz foo_bar", rule.query()).unwrap().unwrap();
+        assert_eq!(replacement.output(), "SpamBaz spam_bar");
+    }
+
+    #[test]
+    fn test_case_escapes() {
+        let contents = r#"
+[[rule]]
+pattern = '(max)'
+replacement = '\U$1'
+case-escapes = true
+
+[[rule]]
+pattern = '(min)'
+replacement = '\U$1'
+"#;
+        let rules_file: RulesFile = toml::from_str(contents).unwrap();
+        let rules: Vec<_> = rules_file
+            .rules
+            .into_iter()
+            .map(|spec| Rule::from_spec(spec, &Settings::default()).unwrap())
+            .collect();
+        assert_eq!(
+            replace("max", rules[0].query()).unwrap().unwrap().output(),
+            "MAX"
+        );
+        assert_eq!(
+            replace("min", rules[1].query()).unwrap().unwrap().output(),
+            r"\Umin"
+        );
+    }
+
+    #[test]
+    fn test_reject_engine_in_simple_mode() {
+        let contents = r#"
+[[rule]]
+pattern = "old"
+replacement = "new"
+mode = "simple"
+engine = "fancy"
+"#;
+        let mut rules_file: RulesFile = toml::from_str(contents).unwrap();
+        let spec = rules_file.rules.remove(0);
+        assert!(Rule::from_spec(spec, &Settings::default()).is_err());
+    }
+}
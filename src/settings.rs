@@ -1,4 +1,8 @@
+use std::path::PathBuf;
+
 use crate::console::Verbosity;
+use crate::line_filter::LineFilter;
+use crate::syntax::SyntaxFilter;
 
 #[derive(Debug, Default)]
 /// Settings applied for a DirectoryPatcher run
@@ -17,4 +21,52 @@ pub struct Settings {
     pub ignored_file_types: Vec<String>,
     /// Exits without an error when no matches are found (default: false)
     pub allow_empty: bool,
+    /// If true, run the query on the whole contents of each file instead
+    /// of line by line, so that matches can span several lines (default: false)
+    pub multiline: bool,
+    /// If true, ask the user whether to apply each replacement (default: false)
+    pub interactive: bool,
+    /// Number of context lines in unified diffs (default: 3)
+    pub context_lines: Option<usize>,
+    /// If set, also write the changes as a unified diff to this path (default: None)
+    pub patch_file: Option<PathBuf>,
+    /// Number of threads used to walk and patch files. 0 means
+    /// choosing automatically (default: 0)
+    pub threads: usize,
+    /// If set, save the original contents of the patched files in a new
+    /// subdirectory of this path, so that the run can be undone (default: None)
+    pub history_dir: Option<PathBuf>,
+    /// If true, keep the modification time of the patched files (default: false)
+    pub preserve_mtime: bool,
+    /// Encoding used to read files without a byte order mark.
+    /// None means UTF-8 (default: None)
+    pub encoding: Option<&'static encoding_rs::Encoding>,
+    /// If set, skip files bigger than this number of bytes (default: None)
+    pub max_filesize: Option<u64>,
+    /// If true, only patch files tracked by git (default: false)
+    pub git_tracked: bool,
+    /// If set, only patch files that differ from this git revision,
+    /// including untracked files (default: None)
+    pub changed_since: Option<String>,
+    /// If true, write changes even to files with uncommitted changes in
+    /// their git repository (default: false)
+    pub allow_dirty: bool,
+    /// If set, save the replacements to this path, so that they can be
+    /// applied later with `ruplacer apply` (default: None)
+    pub plan_file: Option<PathBuf>,
+    /// If true, also rename the files and directories whose names
+    /// match (default: false)
+    pub rename_paths: bool,
+    /// Restrict the matches to some kinds of syntax nodes, like identifiers
+    /// or comments (default: no restriction)
+    pub syntax_filter: SyntaxFilter,
+    /// Restrict the matches to the lines matching, or not matching, some
+    /// regexes, and outside some regions (default: no restriction)
+    pub line_filter: LineFilter,
+    /// If true, skip files containing a "@generated" or "DO NOT EDIT"
+    /// marker in their first lines (default: false)
+    pub skip_generated: bool,
+    /// Files never patched, even when they are inside the walked
+    /// directory, like the rules file (default: empty)
+    pub excluded_files: Vec<PathBuf>,
 }
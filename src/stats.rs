@@ -1,4 +1,63 @@
 use inflector::string::pluralize::to_plural;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Why a file was not patched
+pub enum SkipReason {
+    /// The file looks like binary data
+    Binary,
+    /// The file is marked as generated, and the `skip_generated` setting is set
+    Generated,
+    /// The file is not valid text in the given encoding
+    InvalidEncoding { encoding: String, offset: usize },
+    /// The file could not be read
+    PermissionDenied,
+    /// The file is bigger than the `max_filesize` setting
+    TooLarge { size: u64 },
+    /// Some replacements can't be represented in the encoding of the file
+    Unencodable { encoding: String },
+    /// The regex engine failed to run the query on the file
+    MatchFailed { message: String },
+    /// The syntax filter is set, but can't parse the language of the file
+    UnsupportedLanguage,
+}
+
+impl SkipReason {
+    /// A short description of the kind of reason, used to group skipped files
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SkipReason::Binary => "binary",
+            SkipReason::Generated => "generated",
+            SkipReason::InvalidEncoding { .. } => "undecodable",
+            SkipReason::PermissionDenied => "permission denied",
+            SkipReason::TooLarge { .. } => "too large",
+            SkipReason::Unencodable { .. } => "unencodable",
+            SkipReason::MatchFailed { .. } => "match failed",
+            SkipReason::UnsupportedLanguage => "unsupported language",
+        }
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SkipReason::Binary => write!(f, "binary file"),
+            SkipReason::Generated => write!(f, "generated file"),
+            SkipReason::InvalidEncoding { encoding, offset } => {
+                write!(f, "invalid {encoding} at byte {offset}")
+            }
+            SkipReason::PermissionDenied => write!(f, "permission denied"),
+            SkipReason::TooLarge { size } => write!(f, "too large ({size} bytes)"),
+            SkipReason::Unencodable { encoding } => {
+                write!(f, "replacements can't be encoded as {encoding}")
+            }
+            SkipReason::MatchFailed { message } => write!(f, "match failed: {message}"),
+            SkipReason::UnsupportedLanguage => {
+                write!(f, "language not supported by --only and --skip")
+            }
+        }
+    }
+}
 
 #[derive(Default, Debug)]
 /// Statistics about a run of DirectoryPatcher
@@ -6,6 +65,9 @@ pub struct Stats {
     matching_files: usize,
     matching_lines: usize,
     total_replacements: usize,
+    rules: Vec<Stats>,
+    skipped: Vec<(PathBuf, SkipReason)>,
+    renamed_paths: usize,
 }
 
 impl Stats {
@@ -18,6 +80,21 @@ impl Stats {
         self.total_replacements += replacements;
     }
 
+    pub(crate) fn update_rule(&mut self, index: usize, lines: usize, replacements: usize) {
+        if self.rules.len() <= index {
+            self.rules.resize_with(index + 1, Stats::default);
+        }
+        self.rules[index].update(lines, replacements);
+    }
+
+    pub(crate) fn update_skipped(&mut self, path: &Path, reason: SkipReason) {
+        self.skipped.push((path.to_path_buf(), reason));
+    }
+
+    pub(crate) fn update_renames(&mut self, renamed_paths: usize) {
+        self.renamed_paths += renamed_paths;
+    }
+
     /// Number of matching files
     pub fn matching_files(&self) -> usize {
         self.matching_files
@@ -32,6 +109,54 @@ impl Stats {
     pub fn total_replacements(&self) -> usize {
         self.total_replacements
     }
+
+    /// Statistics for each rule, in the order the rules were given
+    /// (rules that never matched may be missing at the end)
+    pub fn rules(&self) -> &[Stats] {
+        &self.rules
+    }
+
+    /// Number of files and directories that were renamed
+    pub fn renamed_paths(&self) -> usize {
+        self.renamed_paths
+    }
+
+    /// Files that were not patched, along with the reason why
+    pub fn skipped(&self) -> &[(PathBuf, SkipReason)] {
+        &self.skipped
+    }
+
+    /// Number of skipped files for each kind of reason, in the order
+    /// they were first found
+    pub fn skipped_by_kind(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(&'static str, usize)> = vec![];
+        for (_, reason) in &self.skipped {
+            match counts.iter_mut().find(|(kind, _)| *kind == reason.kind()) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((reason.kind(), 1)),
+            }
+        }
+        counts
+    }
+
+    /// Something like "3 skipped files (2 binary, 1 too large)", or
+    /// None if no file was skipped
+    pub fn skipped_summary(&self) -> Option<String> {
+        if self.skipped.is_empty() {
+            return None;
+        }
+        let details: Vec<_> = self
+            .skipped_by_kind()
+            .iter()
+            .map(|(kind, count)| format!("{count} {kind}"))
+            .collect();
+        Some(format!(
+            "{} skipped {} ({})",
+            self.skipped.len(),
+            pluralize("file", self.skipped.len()),
+            details.join(", ")
+        ))
+    }
 }
 
 fn pluralize(input: &str, num: usize) -> String {
@@ -64,6 +189,7 @@ mod tests {
             matching_files: 2,
             total_replacements: 4,
             matching_lines: 1,
+            ..Default::default()
         };
         let actual = stats.to_string();
         assert_eq!(actual, "4 replacements on 2 matching files");
@@ -72,8 +198,23 @@ mod tests {
             matching_files: 1,
             total_replacements: 2,
             matching_lines: 1,
+            ..Default::default()
         };
         let actual = stats.to_string();
         assert_eq!(actual, "2 replacements on 1 matching file");
     }
+
+    #[test]
+    fn test_skipped_summary() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.skipped_summary(), None);
+
+        stats.update_skipped(Path::new("a.png"), SkipReason::Binary);
+        stats.update_skipped(Path::new("big.txt"), SkipReason::TooLarge { size: 42 });
+        stats.update_skipped(Path::new("b.png"), SkipReason::Binary);
+        assert_eq!(
+            stats.skipped_summary().unwrap(),
+            "3 skipped files (2 binary, 1 too large)"
+        );
+    }
 }
@@ -0,0 +1,341 @@
+use anyhow::{Result, anyhow, bail};
+use std::collections::HashMap;
+
+/// A structural rewrite, inspired by comby: the pattern contains holes
+/// like `:[name]`, and the replacement uses the text they matched.
+///
+/// For instance, `assert_eq!(:[a], :[b])` -> `assert_eq!(:[b], :[a])`.
+///
+///  * Holes match any text where parentheses, brackets and braces are
+///    balanced. Delimiters inside double-quoted string literals and character
+///    literals, like `'('`, are not taken into account. Other single quotes,
+///    like Rust lifetimes, are ignored
+///  * When a hole is used several times in the pattern, it must match the
+///    same text each time. `:[_]` matches anything and can't be used in the replacement
+///  * Whitespace in the pattern matches any non-empty run of whitespace
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    pattern: Vec<Part>,
+    replacement: Vec<Segment>,
+}
+
+// A part of the pattern
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Whitespace,
+    Hole(String),
+}
+
+// A part of a template, before whitespace is handled
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    Hole(String),
+}
+
+impl Rewrite {
+    /// Parse a pattern and its replacement, failing on invalid holes, or on
+    /// holes of the replacement missing from the pattern
+    pub fn new(pattern: &str, replacement: &str) -> Result<Self> {
+        let mut parts = vec![];
+        for segment in parse_segments(pattern)? {
+            match segment {
+                Segment::Text(text) => split_whitespace(&text, &mut parts),
+                Segment::Hole(name) => parts.push(Part::Hole(name)),
+            }
+        }
+        if parts.is_empty() {
+            bail!("The pattern is empty");
+        }
+        let replacement = parse_segments(replacement)?;
+        for segment in &replacement {
+            if let Segment::Hole(name) = segment {
+                let known = name != "_"
+                    && parts
+                        .iter()
+                        .any(|part| matches!(part, Part::Hole(other) if other == name));
+                if !known {
+                    bail!("The hole :[{name}] is not defined in the pattern");
+                }
+            }
+        }
+        Ok(Self {
+            pattern: parts,
+            replacement,
+        })
+    }
+
+    /// Find the first match in `input`, and return its index, the matched
+    /// text and the replacement
+    pub(crate) fn replace(&self, input: &str) -> Option<(usize, String, String)> {
+        for (start, _) in input.char_indices() {
+            let mut captures = HashMap::new();
+            if let Some(end) = self.match_at(0, input, start, &mut captures) {
+                let output = self.render(&captures);
+                return Some((start, input[start..end].to_string(), output));
+            }
+        }
+        None
+    }
+
+    // Try to match the pattern, starting at its part number `index`, at
+    // the position `pos` of the input. Return where the match ends
+    fn match_at<'a>(
+        &self,
+        index: usize,
+        input: &'a str,
+        pos: usize,
+        captures: &mut HashMap<String, &'a str>,
+    ) -> Option<usize> {
+        let Some(part) = self.pattern.get(index) else {
+            return Some(pos);
+        };
+        let rest = &input[pos..];
+        match part {
+            Part::Literal(literal) => {
+                if !rest.starts_with(literal.as_str()) {
+                    return None;
+                }
+                self.match_at(index + 1, input, pos + literal.len(), captures)
+            }
+            Part::Whitespace => {
+                let length = rest.len() - rest.trim_start().len();
+                if length == 0 {
+                    return None;
+                }
+                self.match_at(index + 1, input, pos + length, captures)
+            }
+            Part::Hole(name) => {
+                let is_last = index + 1 == self.pattern.len();
+                let mut ends = balanced_ends(rest, is_last);
+                // Note: holes match as little text as possible, except the
+                // last one, which matches as much text as possible
+                if is_last {
+                    ends.reverse();
+                }
+                for end in ends {
+                    let text = &rest[..end];
+                    let previous = captures.get(name.as_str()).copied();
+                    if name != "_" && previous.is_some_and(|previous| previous != text) {
+                        continue;
+                    }
+                    captures.insert(name.clone(), text);
+                    if let Some(end) = self.match_at(index + 1, input, pos + end, captures) {
+                        return Some(end);
+                    }
+                    match previous {
+                        Some(previous) => captures.insert(name.clone(), previous),
+                        None => captures.remove(name.as_str()),
+                    };
+                }
+                None
+            }
+        }
+    }
+
+    fn render(&self, captures: &HashMap<String, &str>) -> String {
+        let mut output = String::new();
+        for segment in &self.replacement {
+            match segment {
+                Segment::Text(text) => output.push_str(text),
+                Segment::Hole(name) => output.push_str(captures.get(name.as_str()).unwrap_or(&"")),
+            }
+        }
+        output
+    }
+}
+
+// Split a template into text and holes
+fn parse_segments(template: &str) -> Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find(":[") {
+        if start > 0 {
+            segments.push(Segment::Text(rest[..start].to_string()));
+        }
+        let after = &rest[start + 2..];
+        let end = after
+            .find(']')
+            .ok_or_else(|| anyhow!("Missing ']' after ':[{after}'"))?;
+        let name = &after[..end];
+        if name.is_empty() || !name.chars().all(|c| c == '_' || c.is_alphanumeric()) {
+            bail!("Invalid hole name: '{name}'");
+        }
+        segments.push(Segment::Hole(name.to_string()));
+        rest = &after[end + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest.to_string()));
+    }
+    Ok(segments)
+}
+
+fn split_whitespace(text: &str, parts: &mut Vec<Part>) {
+    let mut literal = String::new();
+    for c in text.chars() {
+        if !c.is_whitespace() {
+            literal.push(c);
+            continue;
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(std::mem::take(&mut literal)));
+        }
+        if !matches!(parts.last(), Some(Part::Whitespace)) {
+            parts.push(Part::Whitespace);
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+}
+
+// Return the lengths of the prefixes of `input` where delimiters are
+// balanced, in increasing order. Stop at the first unbalanced closing
+// delimiter, or, for the last hole of a pattern, at the end of the line
+fn balanced_ends(input: &str, stop_at_newline: bool) -> Vec<usize> {
+    let mut ends = vec![0];
+    let mut stack = vec![];
+    let mut chars = input.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\n' if stop_at_newline && stack.is_empty() => break,
+            '(' | '[' | '{' => stack.push(c),
+            ')' | ']' | '}' => {
+                let opening = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                if stack.pop() != Some(opening) {
+                    break;
+                }
+            }
+            '"' | '\'' => {
+                // Skip the literal, if it is terminated on the same line
+                let rest = &input[i + 1..];
+                let end = match c {
+                    '"' => string_literal_len(rest),
+                    _ => char_literal_len(rest),
+                };
+                if let Some(end) = end {
+                    let end = i + 1 + end;
+                    while chars.next_if(|&(j, _)| j <= end).is_some() {}
+                }
+            }
+            _ => (),
+        }
+        if stack.is_empty() {
+            let next = chars.peek().map_or(input.len(), |&(j, _)| j);
+            ends.push(next);
+        }
+    }
+    ends
+}
+
+// Return the index of the closing quote of a double-quoted string literal
+// starting just before `input`
+fn string_literal_len(input: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            '\n' => return None,
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(i),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+// Same as `string_literal_len`, for a character literal like 'a', '\n' or
+// '\u{1F600}'. Return None for other single quotes, like the ones of Rust
+// lifetimes or apostrophes
+fn char_literal_len(input: &str) -> Option<usize> {
+    let mut chars = input.char_indices();
+    match chars.next()? {
+        (_, '\\') => {
+            // Note: the longest escape sequence is '\u{10FFFF}'
+            chars.next()?;
+            chars.take(9).find(|&(_, c)| c == '\'').map(|(i, _)| i)
+        }
+        (_, '\'' | '\n') => None,
+        (_, _) => chars.next().filter(|&(_, c)| c == '\'').map(|(i, _)| i),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(pattern: &str, replacement: &str, input: &str) -> Option<String> {
+        let rewrite = Rewrite::new(pattern, replacement).unwrap();
+        let (index, input_text, output_text) = rewrite.replace(input)?;
+        Some(format!(
+            "{}{}{}",
+            &input[..index],
+            output_text,
+            &input[index + input_text.len()..]
+        ))
+    }
+
+    #[test]
+    fn test_balanced_holes() {
+        assert_eq!(
+            rewrite(
+                "assert_eq!(:[a], :[b])",
+                "assert_eq!(:[b], :[a])",
+                "assert_eq!(f(x, y), vec![1, 2]);"
+            )
+            .unwrap(),
+            "assert_eq!(vec![1, 2], f(x, y));"
+        );
+        assert_eq!(
+            rewrite("foo(:[args])", "bar(:[args])", "x = foo(\"(\", ')');").unwrap(),
+            "x = bar(\"(\", ')');"
+        );
+        assert!(rewrite("foo(:[args])", "bar()", "foo(()").is_none());
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(
+            rewrite(
+                "foo<:[l]>(:[args])",
+                "bar<:[l]>(:[args])",
+                "fn foo<'a>(x: &'a str) {"
+            )
+            .unwrap(),
+            "fn bar<'a>(x: &'a str) {"
+        );
+        for input in [r"f('(')", r"f('\'')", r"f('\u{28}')"] {
+            let expected = input.replacen('f', "g", 1);
+            assert_eq!(rewrite("f(:[c])", "g(:[c])", input).unwrap(), expected);
+        }
+        assert_eq!(
+            rewrite("f(:[s])", "g(:[s])", r#"f("it's (") "#).unwrap(),
+            r#"g("it's (") "#
+        );
+    }
+
+    #[test]
+    fn test_whitespace_and_repeated_holes() {
+        assert_eq!(
+            rewrite(":[x] == :[x]", "true", "if a.b  ==  a.b {").unwrap(),
+            "if true {"
+        );
+        assert_eq!(
+            rewrite("f(:[x], :[x])", "g(:[x])", "f(a, a)").unwrap(),
+            "g(a)"
+        );
+        assert!(rewrite("f(:[x], :[x])", "g(:[x])", "f(a, b)").is_none());
+    }
+
+    #[test]
+    fn test_invalid_rewrites() {
+        assert!(Rewrite::new("foo(:[a", "").is_err());
+        assert!(Rewrite::new("foo(:[a])", ":[b]").is_err());
+        assert!(Rewrite::new("foo(:[_])", ":[_]").is_err());
+        assert!(Rewrite::new("", "").is_err());
+    }
+}
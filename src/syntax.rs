@@ -0,0 +1,247 @@
+use anyhow::{Result, anyhow};
+use std::path::Path;
+
+use crate::replacer::Fragments;
+
+/// A kind of syntax node, as used by the `--only` and `--skip` command line options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Identifiers,
+    Strings,
+    Comments,
+}
+
+impl std::str::FromStr for NodeKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<NodeKind> {
+        if !cfg!(feature = "syntax") {
+            return Err(anyhow!("ruplacer was built without the 'syntax' feature"));
+        }
+        match s {
+            "identifiers" => Ok(NodeKind::Identifiers),
+            "strings" => Ok(NodeKind::Strings),
+            "comments" => Ok(NodeKind::Comments),
+            _ => Err(anyhow!(
+                "Choose between 'identifiers', 'strings', or 'comments'"
+            )),
+        }
+    }
+}
+
+impl NodeKind {
+    // Tell whether a tree-sitter node kind, like "line_comment" or
+    // "type_identifier", is of this kind. The names of the node kinds
+    // follow the same conventions in all the supported grammars
+    #[cfg(feature = "syntax")]
+    fn matches(self, kind: &str) -> bool {
+        match self {
+            NodeKind::Identifiers => kind.ends_with("identifier"),
+            NodeKind::Strings => kind.contains("string"),
+            NodeKind::Comments => kind.contains("comment"),
+        }
+    }
+}
+
+/// Restrict the matches to some kinds of syntax nodes, by parsing the
+/// files with tree-sitter. Files written in other languages than C, Go,
+/// JavaScript, Python, Rust and TypeScript are skipped
+#[derive(Debug, Clone, Default)]
+pub struct SyntaxFilter {
+    /// If not empty, only keep the matches inside nodes of these kinds
+    pub only: Vec<NodeKind>,
+    /// Drop the matches overlapping nodes of these kinds
+    pub skip: Vec<NodeKind>,
+}
+
+impl SyntaxFilter {
+    /// Whether the filter keeps every match
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.skip.is_empty()
+    }
+
+    /// Whether the file can be patched: its language must be known,
+    /// unless the filter is empty
+    pub(crate) fn supports(&self, path: &Path) -> bool {
+        self.is_empty() || parser::language(path).is_some()
+    }
+
+    /// Only keep the fragments allowed by the filter. `contents` is parsed
+    /// according to the language of `path`
+    pub(crate) fn filter(&self, path: &Path, contents: &str, fragments: Fragments) -> Fragments {
+        if self.is_empty() {
+            return fragments;
+        }
+        let nodes = parser::parse(path, contents, &self.only, &self.skip);
+        fragments.filter_map(|input, output| {
+            let (start, end) = (input.index, input.index + input.text.len());
+            let inside = |kind: &NodeKind| {
+                nodes
+                    .iter()
+                    .any(|(k, range)| k == kind && range.start <= start && end <= range.end)
+            };
+            let overlaps = |kind: &NodeKind| {
+                nodes
+                    .iter()
+                    .any(|(k, range)| k == kind && range.start < end && start < range.end)
+            };
+            let keep = (self.only.is_empty() || self.only.iter().any(inside))
+                && !self.skip.iter().any(overlaps);
+            keep.then(|| output.text.clone())
+        })
+    }
+}
+
+#[cfg(feature = "syntax")]
+mod parser {
+    use std::ops::Range;
+    use std::path::Path;
+    use std::sync::OnceLock;
+
+    use super::NodeKind;
+
+    // The supported languages, along with the name of their file type in
+    // the `ignore` crate
+    const LANGUAGES: &[&str] = &["c", "go", "js", "py", "rust", "ts"];
+
+    /// Return the tree-sitter language of the file, based on the default
+    /// file types of the `ignore` crate (the ones used by `--type`)
+    pub(super) fn language(path: &Path) -> Option<tree_sitter::Language> {
+        static TYPES: OnceLock<Vec<(&str, ignore::types::Types)>> = OnceLock::new();
+        let types = TYPES.get_or_init(|| {
+            LANGUAGES
+                .iter()
+                .map(|&name| {
+                    let mut builder = ignore::types::TypesBuilder::new();
+                    builder.add_defaults();
+                    builder.select(name);
+                    let types = builder.build().expect("default file types should be valid");
+                    (name, types)
+                })
+                .collect()
+        });
+        let (name, _) = types
+            .iter()
+            .find(|(_, types)| types.matched(path, false).is_whitelist())?;
+        let language = match *name {
+            "c" => tree_sitter_c::LANGUAGE,
+            "go" => tree_sitter_go::LANGUAGE,
+            "js" => tree_sitter_javascript::LANGUAGE,
+            "py" => tree_sitter_python::LANGUAGE,
+            "rust" => tree_sitter_rust::LANGUAGE,
+            "ts" if path.extension().is_some_and(|e| e == "tsx") => {
+                tree_sitter_typescript::LANGUAGE_TSX
+            }
+            "ts" => tree_sitter_typescript::LANGUAGE_TYPESCRIPT,
+            _ => return None,
+        };
+        Some(language.into())
+    }
+
+    /// Parse `contents` and return the byte ranges of the nodes of the given kinds
+    pub(super) fn parse(
+        path: &Path,
+        contents: &str,
+        only: &[NodeKind],
+        skip: &[NodeKind],
+    ) -> Vec<(NodeKind, Range<usize>)> {
+        let mut nodes = vec![];
+        let Some(language) = language(path) else {
+            return nodes;
+        };
+        let mut parser = tree_sitter::Parser::new();
+        // Note: this only fails when the grammar was generated by an
+        // incompatible version of tree-sitter
+        if parser.set_language(&language).is_err() {
+            return nodes;
+        }
+        let Some(tree) = parser.parse(contents, None) else {
+            return nodes;
+        };
+        let mut cursor = tree.walk();
+        loop {
+            let node = cursor.node();
+            for kind in only.iter().chain(skip) {
+                if kind.matches(node.kind()) {
+                    nodes.push((*kind, node.byte_range()));
+                }
+            }
+            if cursor.goto_first_child() || cursor.goto_next_sibling() {
+                continue;
+            }
+            loop {
+                if !cursor.goto_parent() {
+                    return nodes;
+                }
+                if cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Note: without the `syntax` feature, NodeKind can't be parsed, so
+// filters are always empty
+#[cfg(not(feature = "syntax"))]
+mod parser {
+    use std::ops::Range;
+    use std::path::Path;
+
+    use super::NodeKind;
+
+    pub(super) fn language(_path: &Path) -> Option<()> {
+        None
+    }
+
+    pub(super) fn parse(
+        _path: &Path,
+        _contents: &str,
+        _only: &[NodeKind],
+        _skip: &[NodeKind],
+    ) -> Vec<(NodeKind, Range<usize>)> {
+        vec![]
+    }
+}
+
+#[cfg(all(test, feature = "syntax"))]
+mod tests {
+    use super::*;
+    use crate::Query;
+    use crate::replacer::{get_fragments, get_output};
+
+    fn replace_with_filter(path: &str, contents: &str, filter: &SyntaxFilter) -> String {
+        let path = Path::new(path);
+        assert!(filter.supports(path));
+        let fragments = get_fragments(contents, &Query::simple("foo", "bar")).unwrap();
+        let fragments = filter.filter(path, contents, fragments);
+        get_output(contents, &fragments)
+    }
+
+    #[test]
+    fn test_only_identifiers() {
+        let filter = SyntaxFilter {
+            only: vec![NodeKind::Identifiers],
+            skip: vec![],
+        };
+        let contents = "// foo\nlet foo = \"foo\";\nfoo_bar(foo);\n";
+        assert_eq!(
+            replace_with_filter("main.rs", contents, &filter),
+            "// foo\nlet bar = \"foo\";\nbar_bar(bar);\n"
+        );
+    }
+
+    #[test]
+    fn test_skip_comments_and_strings() {
+        let filter = SyntaxFilter {
+            only: vec![],
+            skip: vec![NodeKind::Comments, NodeKind::Strings],
+        };
+        let contents = "# foo\nfoo = 'foo'\n";
+        assert_eq!(
+            replace_with_filter("main.py", contents, &filter),
+            "# foo\nbar = 'foo'\n"
+        );
+        assert!(!filter.supports(Path::new("README.md")));
+    }
+}
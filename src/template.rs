@@ -0,0 +1,380 @@
+use anyhow::{Result, bail};
+use inflector::cases::{
+    camelcase::to_camel_case, kebabcase::to_kebab_case, pascalcase::to_pascal_case,
+    screamingsnakecase::to_screaming_snake_case, sentencecase::to_sentence_case,
+    snakecase::to_snake_case, titlecase::to_title_case, traincase::to_train_case,
+};
+
+/// The replacement of a Regex query.
+///
+/// On top of the `$1`, `${1}`, `$name`, `${name}` and `$$` syntax of the regex
+/// crate, templates support converters applied to a group, like `${1:snake}`
+/// or `${name:pascal}`.
+///
+/// When parsed with `parse_with_case_escapes`, they also support sed-like case
+/// conversions: `\U` and `\L` convert the rest of the output to upper and lower
+/// case, until `\E`; `\u` and `\l` only convert the next character. Then, use `\\`
+/// for a literal backslash. Other backslashes are always kept as is.
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Group(Group, Option<Converter>),
+    Case(CaseConversion),
+}
+
+#[derive(Debug, Clone)]
+enum Group {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CaseConversion {
+    Upper,
+    Lower,
+    UpperNext,
+    LowerNext,
+    End,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Converter {
+    Ada,
+    Camel,
+    Kebab,
+    Lower,
+    Pascal,
+    ScreamingSnake,
+    Sentence,
+    Snake,
+    Title,
+    Train,
+    Upper,
+}
+
+impl std::str::FromStr for Converter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let converter = match s {
+            "ada" => Converter::Ada,
+            "camel" => Converter::Camel,
+            "kebab" => Converter::Kebab,
+            "lower" => Converter::Lower,
+            "pascal" => Converter::Pascal,
+            "screaming" => Converter::ScreamingSnake,
+            "sentence" => Converter::Sentence,
+            "snake" => Converter::Snake,
+            "title" => Converter::Title,
+            "train" => Converter::Train,
+            "upper" => Converter::Upper,
+            _ => bail!(
+                "Unknown converter: '{s}'. Choose between 'ada', 'camel', 'kebab', 'lower', 'pascal', \
+                 'screaming', 'sentence', 'snake', 'title', 'train' or 'upper'"
+            ),
+        };
+        Ok(converter)
+    }
+}
+
+impl Converter {
+    fn convert(self, input: &str) -> String {
+        match self {
+            Converter::Ada => to_train_case(input).replace('-', "_"),
+            Converter::Camel => to_camel_case(input),
+            Converter::Kebab => to_kebab_case(input),
+            Converter::Lower => input.to_lowercase(),
+            Converter::Pascal => to_pascal_case(input),
+            Converter::ScreamingSnake => to_screaming_snake_case(input),
+            Converter::Sentence => to_sentence_case(input),
+            Converter::Snake => to_snake_case(input),
+            Converter::Title => to_title_case(input),
+            Converter::Train => to_train_case(input),
+            Converter::Upper => input.to_uppercase(),
+        }
+    }
+}
+
+/// The groups captured by a match, as used when rendering a Template.
+/// This lets templates work with every regex engine
+pub(crate) trait Captured {
+    fn index(&self, index: usize) -> Option<&str>;
+    fn name(&self, name: &str) -> Option<&str>;
+}
+
+impl Captured for regex::Captures<'_> {
+    fn index(&self, index: usize) -> Option<&str> {
+        self.get(index).map(|m| m.as_str())
+    }
+
+    fn name(&self, name: &str) -> Option<&str> {
+        self.name(name).map(|m| m.as_str())
+    }
+}
+
+#[cfg(feature = "fancy")]
+impl Captured for fancy_regex::Captures<'_> {
+    fn index(&self, index: usize) -> Option<&str> {
+        self.get(index).map(|m| m.as_str())
+    }
+
+    fn name(&self, name: &str) -> Option<&str> {
+        self.name(name).map(|m| m.as_str())
+    }
+}
+
+impl Template {
+    /// A template always rendering `text`
+    pub(crate) fn literal(text: &str) -> Self {
+        Self {
+            parts: vec![Part::Literal(text.to_string())],
+        }
+    }
+
+    /// Parse a replacement, failing on invalid group references or converters.
+    /// Backslashes are kept as is
+    pub fn parse(template: &str) -> Result<Self> {
+        Self::parse_impl(template, false)
+    }
+
+    /// Same as `parse`, but also interpret the case conversion escapes
+    pub fn parse_with_case_escapes(template: &str) -> Result<Self> {
+        Self::parse_impl(template, true)
+    }
+
+    fn parse_impl(template: &str, case_escapes: bool) -> Result<Self> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            let part = match c {
+                '\\' if case_escapes => {
+                    let conversion = match rest.chars().next() {
+                        Some('U') => CaseConversion::Upper,
+                        Some('L') => CaseConversion::Lower,
+                        Some('u') => CaseConversion::UpperNext,
+                        Some('l') => CaseConversion::LowerNext,
+                        Some('E') => CaseConversion::End,
+                        Some('\\') => {
+                            rest = &rest[1..];
+                            literal.push('\\');
+                            continue;
+                        }
+                        _ => {
+                            literal.push('\\');
+                            continue;
+                        }
+                    };
+                    rest = &rest[1..];
+                    Part::Case(conversion)
+                }
+                '$' => {
+                    if let Some(after) = rest.strip_prefix('$') {
+                        rest = after;
+                        literal.push('$');
+                        continue;
+                    }
+                    let (part, after) = parse_group(rest)?;
+                    match part {
+                        Some(part) => {
+                            rest = after;
+                            part
+                        }
+                        // Like the regex crate, keep a lone '$' as is
+                        None => {
+                            literal.push('$');
+                            continue;
+                        }
+                    }
+                }
+                c => {
+                    literal.push(c);
+                    continue;
+                }
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(part);
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Expand the template using the groups captured by a match
+    pub(crate) fn render(&self, captures: &impl Captured) -> String {
+        let mut output = String::new();
+        let mut mode = CaseConversion::End;
+        let mut next = None;
+        for part in &self.parts {
+            let text = match part {
+                Part::Literal(text) => text.clone(),
+                Part::Group(group, converter) => {
+                    let text = match group {
+                        Group::Index(index) => captures.index(*index),
+                        Group::Name(name) => captures.name(name),
+                    };
+                    let text = text.unwrap_or("");
+                    match converter {
+                        Some(converter) => converter.convert(text),
+                        None => text.to_string(),
+                    }
+                }
+                Part::Case(
+                    conversion @ (CaseConversion::UpperNext | CaseConversion::LowerNext),
+                ) => {
+                    next = Some(*conversion);
+                    continue;
+                }
+                Part::Case(conversion) => {
+                    mode = *conversion;
+                    continue;
+                }
+            };
+            for c in text.chars() {
+                let conversion = next.take().unwrap_or(mode);
+                match conversion {
+                    CaseConversion::Upper | CaseConversion::UpperNext => {
+                        output.extend(c.to_uppercase())
+                    }
+                    CaseConversion::Lower | CaseConversion::LowerNext => {
+                        output.extend(c.to_lowercase())
+                    }
+                    CaseConversion::End => output.push(c),
+                }
+            }
+        }
+        output
+    }
+}
+
+// Parse what follows a '$': a group name or index, optionally in braces
+// with a converter. Return None if there is no valid group reference,
+// like in '${' or '${}'
+fn parse_group(input: &str) -> Result<(Option<Part>, &str)> {
+    let is_name_char = |c: char| c == '_' || c.is_ascii_alphanumeric();
+    let (reference, converter, rest) = match input.strip_prefix('{') {
+        Some(after) => {
+            let Some(end) = after.find('}') else {
+                return Ok((None, input));
+            };
+            let (reference, converter) = match after[..end].split_once(':') {
+                Some((reference, converter)) => (reference, Some(converter)),
+                None => (&after[..end], None),
+            };
+            if reference.is_empty() || !reference.chars().all(is_name_char) {
+                return Ok((None, input));
+            }
+            let converter = converter.map(str::parse).transpose()?;
+            (reference, converter, &after[end + 1..])
+        }
+        None => {
+            let end = input.find(|c| !is_name_char(c)).unwrap_or(input.len());
+            if end == 0 {
+                return Ok((None, input));
+            }
+            (&input[..end], None, &input[end..])
+        }
+    };
+    let group = match reference.parse() {
+        Ok(index) => Group::Index(index),
+        Err(_) => Group::Name(reference.to_string()),
+    };
+    Ok((Some(Part::Group(group, converter)), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(pattern: &str, template: &str, input: &str) -> String {
+        let regex = regex::Regex::new(pattern).unwrap();
+        let captures = regex.captures(input).unwrap();
+        Template::parse(template).unwrap().render(&captures)
+    }
+
+    fn render_with_case_escapes(pattern: &str, template: &str, input: &str) -> String {
+        let regex = regex::Regex::new(pattern).unwrap();
+        let captures = regex.captures(input).unwrap();
+        Template::parse_with_case_escapes(template)
+            .unwrap()
+            .render(&captures)
+    }
+
+    #[test]
+    fn test_groups() {
+        assert_eq!(
+            render(r"(\w+) (\w+)", "$2 $1", "hello world"),
+            "world hello"
+        );
+        assert_eq!(render(r"(?P<first>\w+)", "${first}s", "cat"), "cats");
+        assert_eq!(render(r"(\w+)", "$$1 costs $", "price"), "$1 costs $");
+    }
+
+    #[test]
+    fn test_backslashes_are_kept_by_default() {
+        assert_eq!(render(r"old", r"C:\\new", "old"), r"C:\\new");
+        assert_eq!(render(r"(\w+)", r"$1\n\u00e9", "old"), r"old\n\u00e9");
+        assert_eq!(render(r"(\w+)", r"\U$1", "old"), r"\Uold");
+    }
+
+    #[test]
+    fn test_case_conversions() {
+        assert_eq!(
+            render_with_case_escapes(r"(\w+) (\w+)", r"\U$1\E $2", "hello world"),
+            "HELLO world"
+        );
+        assert_eq!(
+            render_with_case_escapes(r"(\w+)", r"\u$1", "hello"),
+            "Hello"
+        );
+        assert_eq!(
+            render_with_case_escapes(r"(\w+)", r"\l$1", "HELLO"),
+            "hELLO"
+        );
+        assert_eq!(
+            render_with_case_escapes(r"(\w+)", r"\u\L$1", "hELLO"),
+            "Hello"
+        );
+        assert_eq!(
+            render_with_case_escapes(r"(\w+)", r"\\U$1 \n", "hello"),
+            r"\Uhello \n"
+        );
+    }
+
+    #[test]
+    fn test_converters() {
+        assert_eq!(render(r"(\w+)", "${1:snake}", "FooBar"), "foo_bar");
+        assert_eq!(render(r"(\w+)", "${1:pascal}", "foo_bar"), "FooBar");
+        assert_eq!(render(r"(?P<n>\w+)", "${n:screaming}", "fooBar"), "FOO_BAR");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(Template::parse("${1:unknown}").is_err());
+    }
+
+    #[test]
+    fn test_invalid_braces_are_kept_as_is() {
+        // Note: this is what the regex crate does too
+        assert_eq!(render(r"(\w+)", "${", "old"), "${");
+        assert_eq!(render(r"(\w+)", "${}", "old"), "${}");
+        assert_eq!(render(r"(\w+)", "${1 is $1", "old"), "${1 is old");
+    }
+
+    #[test]
+    fn test_literal() {
+        let template = Template::literal(r"\U$1");
+        let regex = regex::Regex::new("x").unwrap();
+        let captures = regex.captures("x").unwrap();
+        assert_eq!(template.render(&captures), r"\U$1");
+    }
+}
@@ -0,0 +1,19 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+/// A new temporary directory, removed when dropped
+pub(crate) fn temp_dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("test-ruplacer")
+        .tempdir()
+        .unwrap()
+}
+
+/// Write `contents` to the file `name` inside `dir`, and return its path
+pub(crate) fn write_file(dir: &Path, name: &str, contents: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path
+}
@@ -1,13 +1,17 @@
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 use anyhow::Result;
 use tempfile::TempDir;
 
 use ruplacer::Console;
+use ruplacer::LineFilter;
 use ruplacer::Query;
+use ruplacer::Rule;
 use ruplacer::Settings;
+use ruplacer::SkipReason;
 use ruplacer::{DirectoryPatcher, Stats};
 
 fn setup_test(tmp_dir: &TempDir) -> PathBuf {
@@ -53,6 +57,40 @@ fn run_ruplacer(data_path: &Path, settings: Settings) -> Result<Stats> {
     Ok(directory_patcher.stats())
 }
 
+// Run the ruplacer binary from `dir`, writing `input` to its standard
+// input, and return what it printed
+fn run_binary(dir: &Path, args: &[&str], input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ruplacer"))
+        .args(args)
+        .args(["--color", "never"])
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// Run the ruplacer binary from `dir`, check that it fails, and return
+// what it printed on standard error
+fn run_binary_and_fail(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_ruplacer"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    String::from_utf8(output.stderr).unwrap()
+}
+
 fn temp_dir() -> TempDir {
     tempfile::Builder::new()
         .prefix("test-ruplacer")
@@ -157,6 +195,113 @@ fn test_skip_non_utf8_files() {
     run_ruplacer(&data_path, settings).unwrap();
 }
 
+#[test]
+fn test_report_skipped_files() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    fs::write(data_path.join("foo.latin1"), b"old caf\xef\n").unwrap();
+    fs::write(data_path.join("foo.bin"), b"old\0\x01").unwrap();
+    fs::write(data_path.join("big.txt"), "old\n".repeat(1000)).unwrap();
+
+    let settings = Settings {
+        max_filesize: Some(1000),
+        ..Default::default()
+    };
+    let stats = run_ruplacer(&data_path, settings).unwrap();
+
+    let mut skipped: Vec<_> = stats
+        .skipped()
+        .iter()
+        .map(|(path, reason)| (path.file_name().unwrap().to_owned(), reason.clone()))
+        .collect();
+    skipped.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        skipped,
+        vec![
+            ("big.txt".into(), SkipReason::TooLarge { size: 4000 }),
+            ("foo.bin".into(), SkipReason::Binary),
+            (
+                "foo.latin1".into(),
+                SkipReason::InvalidEncoding {
+                    encoding: "UTF-8".to_string(),
+                    offset: 7
+                }
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_skip_files_where_replacements_cant_be_encoded() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    let latin1_path = data_path.join("foo.latin1");
+    fs::write(&latin1_path, b"old caf\xe9\n").unwrap();
+
+    let settings = Settings {
+        encoding: Some(encoding_rs::WINDOWS_1252),
+        ..Default::default()
+    };
+    let console = Console::new();
+    let mut directory_patcher = DirectoryPatcher::new(&console, &data_path, &settings);
+    let query = Query::simple("old", "→");
+    directory_patcher.run(&query).unwrap();
+
+    let stats = directory_patcher.stats();
+    let skipped: Vec<_> = stats
+        .skipped()
+        .iter()
+        .filter(|(path, _)| path.ends_with("foo.latin1"))
+        .map(|(_, reason)| reason.clone())
+        .collect();
+    assert_eq!(
+        skipped,
+        vec![SkipReason::Unencodable {
+            encoding: "windows-1252".to_string()
+        }]
+    );
+    assert_eq!(fs::read(&latin1_path).unwrap(), b"old caf\xe9\n");
+}
+
+#[test]
+fn test_skip_generated_files_and_regions() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    let generated_path = data_path.join("generated.rs");
+    fs::write(&generated_path, "// @generated by a tool\nold\n").unwrap();
+    let regions_path = data_path.join("regions.rs");
+    fs::write(
+        &regions_path,
+        "old\n// BEGIN GENERATED\nold\n// END GENERATED\nold\n",
+    )
+    .unwrap();
+
+    let settings = Settings {
+        skip_generated: true,
+        line_filter: LineFilter {
+            skip_regions: vec![(
+                regex::Regex::new("BEGIN GENERATED").unwrap(),
+                regex::Regex::new("END GENERATED").unwrap(),
+            )],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let stats = run_ruplacer(&data_path, settings).unwrap();
+
+    assert_not_replaced(&generated_path);
+    assert!(
+        stats
+            .skipped()
+            .contains(&(generated_path, SkipReason::Generated))
+    );
+    let contents = fs::read_to_string(&regions_path).unwrap();
+    assert_eq!(
+        contents,
+        "new\n// BEGIN GENERATED\nold\n// END GENERATED\nnew\n"
+    );
+}
+
 fn add_python_file(data_path: &Path) -> PathBuf {
     let py_path = data_path.join("foo.py");
     fs::write(&py_path, "a = 'this is old'\n").unwrap();
@@ -274,3 +419,387 @@ fn test_ignore_file_types_by_incorrect_glob_pattern() {
     let err = run_ruplacer(&data_path, settings).unwrap_err();
     assert!(err.to_string().contains("unrecognized file type"));
 }
+
+#[test]
+fn test_write_patch_file() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    let patch_path = tmp_dir.path().join("changes.patch");
+
+    let settings = Settings {
+        dry_run: true,
+        patch_file: Some(patch_path.clone()),
+        ..Default::default()
+    };
+    run_ruplacer(&data_path, settings).unwrap();
+
+    let top_txt_path = data_path.join("top.txt");
+    assert_not_replaced(&top_txt_path);
+    let patch = fs::read_to_string(&patch_path).unwrap();
+    assert!(patch.contains("+++ b/top.txt"));
+    assert!(patch.contains("-Top: old is nice"));
+    assert!(patch.contains("+Top: new is nice"));
+}
+
+#[test]
+fn test_multiline_output() {
+    let tmp_dir = temp_dir();
+    let data_path = tmp_dir.path();
+    fs::write(data_path.join("foo.txt"), "x = foo(\n    bar)\n").unwrap();
+
+    let output = run_binary(
+        data_path,
+        &["--multiline", r"foo\(\s*(\w+)\)", "foo($1)", "foo.txt"],
+        "",
+    );
+
+    assert!(output.starts_with(
+        "foo.txt:1 - x = foo(\n\
+         foo.txt:2 -     bar)\n\
+         foo.txt:1 + x = foo(bar)\n"
+    ));
+}
+
+#[test]
+fn test_json_output() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+
+    let output = run_binary(
+        &data_path,
+        &["old", "new", "--format", "json", "top.txt"],
+        "",
+    );
+
+    let objects: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(
+        objects,
+        [
+            serde_json::json!({
+                "type": "match",
+                "path": "top.txt",
+                "line": 2,
+                "input_offset": 5,
+                "output_offset": 5,
+                "input": "old",
+                "output": "new",
+                "input_line": "Top: old is nice\n",
+                "output_line": "Top: new is nice\n",
+            }),
+            serde_json::json!({
+                "type": "summary",
+                "dry_run": true,
+                "matching_files": 1,
+                "matching_lines": 1,
+                "total_replacements": 1,
+                "skipped_files": 0,
+                "renamed_paths": 0,
+            }),
+        ]
+    );
+}
+
+#[test]
+fn test_diff_and_format_diff_options() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+
+    for diff in [&["--diff"][..], &["--format", "diff"]] {
+        for other in [&["--go"][..], &["--rename-paths"]] {
+            let args = [&["old", "new"][..], diff, other].concat();
+            let error = run_binary_and_fail(&data_path, &args);
+            assert!(error.contains(other[0]), "{args:?}: {error}");
+        }
+        let args = [&["old", "new", "--context", "1"][..], diff].concat();
+        let output = run_binary(&data_path, &args, "");
+        assert!(output.contains("--- a/top.txt\n+++ b/top.txt\n@@ -1,3 +1,3 @@\n"));
+    }
+    assert_not_replaced(&data_path.join("top.txt"));
+
+    let error = run_binary_and_fail(&data_path, &["old", "new", "--context", "1"]);
+    assert!(error.contains("--context"));
+    let error = run_binary_and_fail(
+        &data_path,
+        &["old", "new", "--format", "json", "--context", "1"],
+    );
+    assert!(error.contains("--context"));
+    let patch_path = tmp_dir.path().join("changes.patch");
+    let patch_path = patch_path.to_str().unwrap();
+    let args = ["old", "new", "--context", "1", "--patch-file", patch_path];
+    run_binary(&data_path, &args, "");
+}
+
+#[test]
+fn test_diff_headers_are_relative_to_the_walked_directory() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+
+    let absolute_path = data_path.canonicalize().unwrap();
+    let absolute_path = absolute_path.to_str().unwrap();
+    let absolute = run_binary(tmp_dir.path(), &["old", "new", "--diff", absolute_path], "");
+    assert!(absolute.contains("--- a/a_dir/sub/foo.txt\n+++ b/a_dir/sub/foo.txt\n"));
+    assert!(absolute.contains("--- a/top.txt\n+++ b/top.txt\n"));
+
+    let parent = run_binary(
+        &data_path.join("a_dir"),
+        &["old", "new", "--diff", ".."],
+        "",
+    );
+    assert_eq!(parent, absolute);
+}
+
+#[test]
+fn test_same_stats_with_one_or_several_threads() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    for i in 0..20 {
+        let dir = data_path.join(format!("dir_{}", i % 3));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("file_{i:02}.txt")), "this is old\n").unwrap();
+    }
+
+    let settings = Settings {
+        dry_run: true,
+        threads: 1,
+        ..Default::default()
+    };
+    let sequential = run_ruplacer(&data_path, settings).unwrap();
+
+    let settings = Settings {
+        dry_run: true,
+        threads: 4,
+        ..Default::default()
+    };
+    let parallel = run_ruplacer(&data_path, settings).unwrap();
+
+    assert_eq!(sequential.matching_files(), parallel.matching_files());
+    assert_eq!(sequential.matching_lines(), parallel.matching_lines());
+    assert_eq!(
+        sequential.total_replacements(),
+        parallel.total_replacements()
+    );
+
+    // Note: the output is printed by the binary, so run it
+    let run_with_threads =
+        |threads: &str| run_binary(&data_path, &["old", "new", "--threads", threads], "");
+    let sequential = run_with_threads("1");
+    let parallel = run_with_threads("4");
+    assert_eq!(sequential, parallel);
+
+    let paths: Vec<PathBuf> = sequential
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(path, _)| PathBuf::from(path))
+        .collect();
+    assert_eq!(paths.len(), 48);
+    let mut sorted = paths.clone();
+    sorted.sort();
+    assert_eq!(paths, sorted);
+}
+
+#[test]
+fn test_apply_rules_in_order() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    let py_path = add_python_file(&data_path);
+    let rules_path = tmp_dir.path().join("rules.toml");
+    fs::write(
+        &rules_path,
+        r#"
+[[rule]]
+pattern = "old"
+replacement = "new"
+
+[[rule]]
+pattern = "this is new"
+replacement = "this is newer"
+mode = "simple"
+types = ["py"]
+"#,
+    )
+    .unwrap();
+
+    let settings = Settings::default();
+    let rules = Rule::from_file(&rules_path, &settings).unwrap();
+    let console = Console::new();
+    let mut directory_patcher = DirectoryPatcher::new(&console, &data_path, &settings);
+    directory_patcher.run_rules(&rules).unwrap();
+    let stats = directory_patcher.stats();
+
+    assert_eq!(stats.rules()[1].matching_files(), 1);
+    assert!(stats.rules()[0].matching_files() > 1);
+    let contents = fs::read_to_string(&py_path).unwrap();
+    assert_eq!(contents, "a = 'this is newer'\n");
+    assert_replaced(&data_path.join("top.txt"));
+}
+
+#[test]
+fn test_apply_plan_from_another_directory() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    run_binary(&data_path, &["old", "new", "--save-plan", "plan.json"], "");
+    assert_not_replaced(&data_path.join("top.txt"));
+
+    let plan_path = data_path.join("plan.json");
+    let plan_path = plan_path.to_str().unwrap();
+    // Note: --color is not an option of the subcommands, so run_binary
+    // can't be used here
+    let output = Command::new(env!("CARGO_BIN_EXE_ruplacer"))
+        .args(["apply", plan_path])
+        .current_dir(tmp_dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Patched 3 files"));
+    assert_replaced(&data_path.join("top.txt"));
+
+    // The files changed since the plan was saved
+    let stderr = run_binary_and_fail(tmp_dir.path(), &["apply", plan_path]);
+    assert!(stderr.contains("changed since the plan was saved"));
+}
+
+#[test]
+fn test_rules_file_inside_the_patched_directory_is_not_patched() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    let rules_path = data_path.join("rules.toml");
+    let rules = "[[rule]]\npattern = \"old\"\nreplacement = \"new\"\n";
+    fs::write(&rules_path, rules).unwrap();
+
+    run_binary(&data_path, &["--rules", "rules.toml", "--go"], "");
+
+    assert_eq!(fs::read_to_string(&rules_path).unwrap(), rules);
+    assert_replaced(&data_path.join("top.txt"));
+}
+
+fn git(dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .current_dir(dir)
+        .status()
+        .expect("Failed to execute git");
+    assert!(status.success());
+}
+
+#[test]
+fn test_refuse_to_patch_dirty_files() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    git(&data_path, &["init", "--quiet"]);
+    git(&data_path, &["add", "."]);
+    git(&data_path, &["commit", "--quiet", "-m", "initial commit"]);
+    let top_txt_path = data_path.join("top.txt");
+    fs::write(&top_txt_path, "old is dirty\n").unwrap();
+
+    let err = run_ruplacer(&data_path, Settings::default()).unwrap_err();
+    assert!(err.to_string().contains("top.txt"));
+    assert_not_replaced(&top_txt_path);
+
+    let settings = Settings {
+        allow_dirty: true,
+        ..Default::default()
+    };
+    run_ruplacer(&data_path, settings).unwrap();
+    assert_replaced(&top_txt_path);
+}
+
+#[test]
+fn test_refuse_to_rename_dirty_paths() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_test(&tmp_dir);
+    git(&data_path, &["init", "--quiet"]);
+    git(&data_path, &["add", "."]);
+    git(&data_path, &["commit", "--quiet", "-m", "initial commit"]);
+    let old_dir = data_path.join("old_dir");
+    fs::create_dir(&old_dir).unwrap();
+    fs::write(old_dir.join("notes.txt"), "nothing to replace\n").unwrap();
+
+    let settings = Settings {
+        rename_paths: true,
+        ..Default::default()
+    };
+    let err = run_ruplacer(&data_path, settings).unwrap_err();
+    assert!(err.to_string().contains("old_dir"));
+    assert!(old_dir.exists());
+    assert_not_replaced(&data_path.join("top.txt"));
+}
+
+#[test]
+fn test_rename_paths() {
+    let tmp_dir = temp_dir();
+    let data_path = tmp_dir.path();
+    fs::create_dir_all(data_path.join("old_dir")).unwrap();
+    fs::write(data_path.join("old_dir/old.txt"), "old\n").unwrap();
+
+    let settings = Settings {
+        rename_paths: true,
+        ..Default::default()
+    };
+    let stats = run_ruplacer(data_path, settings).unwrap();
+
+    assert_eq!(stats.renamed_paths(), 2);
+    assert!(!data_path.join("old_dir").exists());
+    assert_replaced(&data_path.join("new_dir/new.txt"));
+}
+
+#[test]
+fn test_refuse_to_rename_to_existing_paths() {
+    let tmp_dir = temp_dir();
+    let data_path = tmp_dir.path();
+    fs::write(data_path.join("old.txt"), "old\n").unwrap();
+    fs::write(data_path.join("new.txt"), "new\n").unwrap();
+
+    let settings = Settings {
+        rename_paths: true,
+        ..Default::default()
+    };
+    let err = run_ruplacer(data_path, settings).unwrap_err();
+
+    assert!(err.to_string().contains("already taken"));
+    assert_not_replaced(&data_path.join("old.txt"));
+}
+
+fn setup_interactive_test(tmp_dir: &TempDir) -> PathBuf {
+    let data_path = tmp_dir.path().join("data");
+    fs::create_dir(&data_path).unwrap();
+    fs::write(data_path.join("a.txt"), "old 1\nold 2\nold 3\nold 4\n").unwrap();
+    fs::write(data_path.join("b.txt"), "old 5\nold 6\n").unwrap();
+    fs::write(data_path.join("c.txt"), "old 7\n").unwrap();
+    data_path
+}
+
+#[test]
+fn test_interactive_review() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_interactive_test(&tmp_dir);
+
+    // Note: 'a' accepts the rest of a.txt, and 'q' also skips c.txt
+    let answers = "y\nn\na\ne\ncustom\nq\n";
+    let output = run_binary(&data_path, &["old", "new", "--interactive"], answers);
+
+    assert_eq!(output.matches("Apply this replacement").count(), 5);
+    assert!(!output.contains("c.txt"));
+    let read = |name| fs::read_to_string(data_path.join(name)).unwrap();
+    assert_eq!(read("a.txt"), "new 1\nold 2\nnew 3\nnew 4\n");
+    assert_eq!(read("b.txt"), "custom 5\nold 6\n");
+    assert_eq!(read("c.txt"), "old 7\n");
+}
+
+#[test]
+fn test_interactive_review_quits_when_input_is_closed() {
+    let tmp_dir = temp_dir();
+    let data_path = setup_interactive_test(&tmp_dir);
+
+    let output = run_binary(&data_path, &["old", "new", "--interactive"], "y\n");
+
+    assert_eq!(output.matches("Apply this replacement").count(), 2);
+    let read = |name| fs::read_to_string(data_path.join(name)).unwrap();
+    assert_eq!(read("a.txt"), "new 1\nold 2\nold 3\nold 4\n");
+    assert_eq!(read("b.txt"), "old 5\nold 6\n");
+    assert_eq!(read("c.txt"), "old 7\n");
+}